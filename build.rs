@@ -0,0 +1,62 @@
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+// Range and step of the generated RTD tables in degrees, can be overridden
+// using the environment variables of the same name.
+const RTD_TABLE_MIN : i32 = -200;
+const RTD_TABLE_MAX : i32 = 850;
+const RTD_TABLE_STEP : i32 = 10;
+
+/// Callendar-Van Dusen coefficients A, B and C
+struct Coefficients(f64, f64, f64);
+
+const ALPHA_385 : Coefficients = Coefficients(3.9083e-3, -5.775e-7, -4.183e-12);
+const ALPHA_392 : Coefficients = Coefficients(3.9848e-3, -5.870e-7, -4.000e-12);
+
+fn env_or(name: &str, default: i32) -> i32 {
+    println!("cargo:rerun-if-env-changed={}", name);
+    match env::var(name) {
+        Ok(val) => val.parse().unwrap_or_else(|_| panic!("{} must be an integer", name)),
+        Err(_) => default,
+    }
+}
+
+/// Resistance relative to R0 at temperature `t`.
+fn ratio(k: &Coefficients, t: f64) -> f64 {
+    let mut r = 1.0 + k.0 * t + k.1 * t * t;
+    if t < 0.0 {
+        r += k.2 * (t - 100.0) * t * t * t;
+    }
+    r
+}
+
+fn write_table(out: &mut File, name: &str, k: &Coefficients, min: i32, max: i32, step: i32) {
+    writeln!(out, "pub static {} : &[TempPair] = &[", name).unwrap();
+    let mut t = min;
+    while t <= max {
+        // temperature in 1/100 degrees, resistance relative to R0 scaled by 1e6
+        let r = (ratio(k, t as f64) * 1e6).round() as u32;
+        writeln!(out, "    ({}, {}),", t * 100, r).unwrap();
+        t += step;
+    }
+    writeln!(out, "];").unwrap();
+}
+
+fn main() {
+    let min = env_or("RTD_TABLE_MIN", RTD_TABLE_MIN);
+    let max = env_or("RTD_TABLE_MAX", RTD_TABLE_MAX);
+    let step = env_or("RTD_TABLE_STEP", RTD_TABLE_STEP);
+
+    assert!(step > 0, "RTD_TABLE_STEP must be positive");
+    assert!(min < max, "RTD_TABLE_MIN must be below RTD_TABLE_MAX");
+
+    let path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("rtd_table.rs");
+    let mut out = File::create(&path).unwrap();
+
+    write_table(&mut out, "LOOKUP_TABLE_385", &ALPHA_385, min, max, step);
+    write_table(&mut out, "LOOKUP_TABLE_392", &ALPHA_392, min, max, step);
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
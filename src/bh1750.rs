@@ -1,6 +1,24 @@
-use spi;
-use spi::{Spi, SPIResult};
-use cortex_m;
+#[macro_use]
+#[allow(unused_imports)]
+use debug;
+use stm32::I2C1;
+
+use rtfm::{Resource, Threshold};
+use i2cbus;
+
+/// Address with the ADDR pin low, 0x5C with ADDR high.
+pub const ADDRESS : u8 = 0x23;
+
+/// Measurement time register after power on.
+pub const MTREG_DEFAULT : u8 = 69;
+pub const MTREG_MIN : u8 = 31;
+pub const MTREG_MAX : u8 = 254;
+
+/// Auto-ranging aims for readings around `RANGE_TARGET` counts and changes
+/// MTreg once they leave `RANGE_LOW` to `RANGE_HIGH`.
+const RANGE_TARGET : u32 = 20_000;
+const RANGE_LOW : u16 = 2_000;
+const RANGE_HIGH : u16 = 50_000;
 
 pub enum OpCode {
     PowerDown = 0b00000000,
@@ -18,4 +36,324 @@ pub enum OpCode {
     /// Start measurement at 0.5 lx resolution.
     /// Measurement Time is typically 120 ms.
     ContinuousHResolutionMode2 = 0b00010001,
+
+    /// Continous Low Resolution Mode
+    /// 
+    /// Start measurement at 4 lx resolution.
+    /// Measurement Time is typically 16 ms.
+    ContinuousLResolutionMode = 0b00010011,
+
+    /// One Time High Resolution Mode
+    /// 
+    /// Start a single measurement at 1 lx resolution, the device powers down
+    /// afterwards.
+    OneTimeHResolutionMode = 0b00100000,
+
+    /// One Time High Resolution Mode 2
+    /// 
+    /// Start a single measurement at 0.5 lx resolution.
+    OneTimeHResolutionMode2 = 0b00100001,
+
+    /// One Time Low Resolution Mode
+    /// 
+    /// Start a single measurement at 4 lx resolution.
+    OneTimeLResolutionMode = 0b00100011,
+
+    /// Change Measurement Time (High Bit)
+    /// 
+    /// Bits 7 to 5 of MTreg go into the lowest 3 bits.
+    MeasurementTimeHigh = 0b01000000,
+
+    /// Change Measurement Time (Low Bit)
+    /// 
+    /// Bits 4 to 0 of MTreg go into the lowest 5 bits.
+    MeasurementTimeLow = 0b01100000,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    ContinuousH,
+    ContinuousH2,
+    ContinuousL,
+    OneTimeH,
+    OneTimeH2,
+    OneTimeL,
+}
+
+impl Mode {
+    pub fn opcode(&self) -> OpCode {
+        match *self {
+            Mode::ContinuousH => OpCode::ContinuousHResolutionMode,
+            Mode::ContinuousH2 => OpCode::ContinuousHResolutionMode2,
+            Mode::ContinuousL => OpCode::ContinuousLResolutionMode,
+            Mode::OneTimeH => OpCode::OneTimeHResolutionMode,
+            Mode::OneTimeH2 => OpCode::OneTimeHResolutionMode2,
+            Mode::OneTimeL => OpCode::OneTimeLResolutionMode,
+        }
+    }
+
+    pub fn one_time(&self) -> bool {
+        match *self {
+            Mode::OneTimeH | Mode::OneTimeH2 | Mode::OneTimeL => true,
+            _ => false
+        }
+    }
+
+    /// Maximum measurement time in TIM2 ticks, which scales with MTreg.
+    pub fn measurement_ticks(&self, mtreg: u8) -> u64 {
+        let ticks = match *self {
+            Mode::ContinuousL | Mode::OneTimeL => 24,
+            _ => 180,
+        };
+        (ticks * mtreg as u64 + MTREG_DEFAULT as u64 - 1) / MTREG_DEFAULT as u64
+    }
+
+    /// Counts per lux are doubled in the H2 modes.
+    fn divisor(&self) -> u32 {
+        match *self {
+            Mode::ContinuousH2 | Mode::OneTimeH2 => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Illuminance in 1/100 lux of a raw measurement,
+/// lux = raw / 1.2 * MTREG_DEFAULT / mtreg.
+pub fn lux(raw: u16, mode: Mode, mtreg: u8) -> u32 {
+    let num = raw as u64 * 1000 * MTREG_DEFAULT as u64;
+    let den = 12 * mode.divisor() as u64 * mtreg as u64;
+    (num / den) as u32
+}
+
+/// MTreg bringing a reading of `raw` at `mtreg` to about `RANGE_TARGET`
+/// counts, or `None` if the reading is within range.
+fn auto_range(raw: u16, mtreg: u8) -> Option<u8> {
+    if raw >= RANGE_LOW && raw <= RANGE_HIGH {
+        return None;
+    }
+
+    let raw = if raw == 0 { 1 } else { raw as u32 };
+    let target = mtreg as u32 * RANGE_TARGET / raw;
+    let target = if target < MTREG_MIN as u32 {
+        MTREG_MIN
+    } else if target > MTREG_MAX as u32 {
+        MTREG_MAX
+    } else {
+        target as u8
+    };
+
+    if target == mtreg { None } else { Some(target) }
 }
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The device did not acknowledge or the bus got stuck
+    Bus,
+    /// The I2C queue is full
+    Busy,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    PoweredDown,
+    PoweredOn,
+    /// Measuring until the given tick
+    Measuring(u64),
+    /// Waiting for the queued read of the measurement
+    Reading,
+}
+
+/// Result of the last queued read, set from the I2C interrupt.
+static mut RESULT : Option<Result<u16, Error>> = None;
+/// Set from the I2C interrupt if a queued command was not acknowledged.
+static mut WRITE_FAILED : bool = false;
+
+fn on_read(result: Result<&[u8], i2cbus::Error>) {
+    let result = match result {
+        Ok(data) => Ok((data[0] as u16) << 8 | data[1] as u16),
+        Err(_) => Err(Error::Bus)
+    };
+    unsafe { RESULT = Some(result); }
+}
+
+fn on_write(result: Result<&[u8], i2cbus::Error>) {
+    if result.is_err() {
+        unsafe { WRITE_FAILED = true; }
+    }
+}
+
+/// BH1750 ambient light sensor on I2C1.
+///
+/// Commands and reads are queued on the shared bus, see `i2cbus`. The
+/// measurement time is waited for with `poll` on the TIM2 tick, which also
+/// picks up the result of the read once the transaction finished.
+pub struct Bh1750 {
+    address : u8,
+    mode : Mode,
+    mtreg : u8,
+    auto_range : bool,
+    state : State,
+}
+
+impl Bh1750 {
+    pub fn new(address: u8) -> Bh1750 {
+        Bh1750 {
+            address: address,
+            mode: Mode::ContinuousH,
+            mtreg: MTREG_DEFAULT,
+            auto_range: false,
+            state: State::PoweredDown,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn mtreg(&self) -> u8 {
+        self.mtreg
+    }
+
+    /// Pick MTreg from each reading so that dim light is measured with a
+    /// longer and bright light with a shorter measurement time.
+    pub fn set_auto_range(&mut self, auto_range: bool) {
+        self.auto_range = auto_range;
+    }
+
+    /// Set the measurement time register, clamped to `MTREG_MIN` to
+    /// `MTREG_MAX`. A running measurement uses it from the next one on.
+    pub fn set_mtreg<S>(&mut self, mtreg: u8, t: &mut Threshold, i2c: &S) -> Result<(), Error>
+    where
+        S : Resource<Data = I2C1>
+    {
+        let mtreg = if mtreg < MTREG_MIN { MTREG_MIN } else if mtreg > MTREG_MAX { MTREG_MAX } else { mtreg };
+        let data = [OpCode::MeasurementTimeHigh as u8 | mtreg >> 5];
+        self.write_bytes(t, i2c, &data)?;
+        let data = [OpCode::MeasurementTimeLow as u8 | mtreg & 0x1F];
+        self.write_bytes(t, i2c, &data)?;
+        self.mtreg = mtreg;
+        Ok(())
+    }
+
+    fn write<S>(&self, t: &mut Threshold, i2c: &S, op: OpCode) -> Result<(), Error>
+    where
+        S : Resource<Data = I2C1>
+    {
+        self.write_bytes(t, i2c, &[op as u8])
+    }
+
+    fn write_bytes<S>(&self, t: &mut Threshold, i2c: &S, data: &[u8]) -> Result<(), Error>
+    where
+        S : Resource<Data = I2C1>
+    {
+        if i2cbus::write(t, i2c, self.address, data, &[], Some(on_write)) {
+            Ok(())
+        } else {
+            Err(Error::Busy)
+        }
+    }
+
+    pub fn power_on<S>(&mut self, t: &mut Threshold, i2c: &S) -> Result<(), Error>
+    where
+        S : Resource<Data = I2C1>
+    {
+        self.write(t, i2c, OpCode::PowerOn)?;
+        self.state = State::PoweredOn;
+        Ok(())
+    }
+
+    pub fn power_down<S>(&mut self, t: &mut Threshold, i2c: &S) -> Result<(), Error>
+    where
+        S : Resource<Data = I2C1>
+    {
+        self.write(t, i2c, OpCode::PowerDown)?;
+        self.state = State::PoweredDown;
+        Ok(())
+    }
+
+    /// Clear the data register, only possible while powered on.
+    pub fn reset<S>(&mut self, t: &mut Threshold, i2c: &S) -> Result<(), Error>
+    where
+        S : Resource<Data = I2C1>
+    {
+        if let State::PoweredDown = self.state {
+            self.power_on(t, i2c)?;
+        }
+        self.write(t, i2c, OpCode::Reset)
+    }
+
+    /// Start measuring in `mode`, the result can be read by `poll` once the
+    /// measurement time has passed.
+    pub fn start<S>(&mut self, now: u64, mode: Mode, t: &mut Threshold, i2c: &S) -> Result<(), Error>
+    where
+        S : Resource<Data = I2C1>
+    {
+        self.write(t, i2c, mode.opcode())?;
+        self.mode = mode;
+        self.state = State::Measuring(now + mode.measurement_ticks(self.mtreg));
+        Ok(())
+    }
+
+    /// Queue reading the measurement once it is due and return the
+    /// illuminance in 1/100 lux once the read finished. Continuous modes keep
+    /// measuring, one-time modes power down. With auto-ranging MTreg is
+    /// adjusted for the following measurement.
+    pub fn poll<S>(&mut self, now: u64, t: &mut Threshold, i2c: &S) -> Option<Result<u32, Error>>
+    where
+        S : Resource<Data = I2C1>
+    {
+        if unsafe { WRITE_FAILED } {
+            unsafe { WRITE_FAILED = false; }
+            return Some(Err(Error::Bus));
+        }
+
+        match self.state {
+            State::Measuring(due) if now >= due => {
+                unsafe { RESULT = None; }
+                if i2cbus::read(t, i2c, self.address, 2, on_read) {
+                    self.state = State::Reading;
+                }
+                return None;
+            }
+            State::Reading => {}
+            _ => return None
+        }
+
+        let raw = match unsafe { RESULT.take() } {
+            Some(Ok(raw)) => raw,
+            Some(Err(e)) => {
+                self.next(now);
+                return Some(Err(e));
+            }
+            None => return None
+        };
+
+        let lux = lux(raw, self.mode, self.mtreg);
+
+        if self.auto_range {
+            if let Some(mtreg) = auto_range(raw, self.mtreg) {
+                // restart so the next measurement uses the new MTreg
+                let restart = self.mode.opcode();
+                let mut changed = self.set_mtreg(mtreg, t, i2c);
+                if changed.is_ok() && !self.mode.one_time() {
+                    changed = self.write(t, i2c, restart);
+                }
+                if let Err(e) = changed {
+                    self.next(now);
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        self.next(now);
+        Some(Ok(lux))
+    }
+
+    fn next(&mut self, now: u64) {
+        self.state = if self.mode.one_time() {
+            State::PoweredDown
+        } else {
+            State::Measuring(now + self.mode.measurement_ticks(self.mtreg))
+        };
+    }
+}
\ No newline at end of file
@@ -0,0 +1,164 @@
+/// Gain of one in `Calibration::TwoPoint`.
+pub const GAIN_ONE : i32 = 1_000_000;
+
+/// Readings which have to stay within `STABLE_TOLERANCE` before a reference
+/// point is captured.
+pub const STABLE_SAMPLES : u32 = 20;
+/// Maximum spread of stable readings in 1/100 degrees.
+pub const STABLE_TOLERANCE : i32 = 5;
+
+/// Correction of a probe, applied to the temperature converted from the RTD
+/// code before it is displayed. All temperatures are in 1/100 degrees.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Calibration {
+    None,
+    /// Added to the measured temperature
+    Offset(i32),
+    /// `measured * gain / GAIN_ONE + offset`
+    TwoPoint { gain: i32, offset: i32 },
+}
+
+impl Calibration {
+    /// Single point calibration from a reading `measured` of a known
+    /// `reference` temperature.
+    pub fn offset(measured: i32, reference: i32) -> Calibration {
+        Calibration::Offset(reference - measured)
+    }
+
+    /// Two point calibration from readings of two known temperatures, e.g.
+    /// ice water and boiling water. Returns `None` if the readings are equal.
+    pub fn two_point(measured_low: i32, reference_low: i32, measured_high: i32, reference_high: i32) -> Option<Calibration> {
+        let measured_span = (measured_high - measured_low) as i64;
+        if measured_span == 0 {
+            return None;
+        }
+
+        let gain = (reference_high - reference_low) as i64 * GAIN_ONE as i64 / measured_span;
+        let offset = reference_low as i64 - measured_low as i64 * gain / GAIN_ONE as i64;
+
+        Some(Calibration::TwoPoint {
+            gain: gain as i32,
+            offset: offset as i32,
+        })
+    }
+
+    pub fn apply(&self, temp: i32) -> i32 {
+        match *self {
+            Calibration::None => temp,
+            Calibration::Offset(offset) => temp + offset,
+            Calibration::TwoPoint { gain, offset } =>
+                (temp as i64 * gain as i64 / GAIN_ONE as i64) as i32 + offset,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Step {
+    /// Waiting for a stable reading of the low (or only) reference
+    Low,
+    /// Waiting for a stable reading of the high reference
+    High,
+    Done,
+}
+
+/// Guided calibration of a probe, capturing the reference points from live
+/// uncalibrated readings once they have settled.
+///
+/// Put the probe into the low reference (ice water) and feed every reading to
+/// `sample`. Once it is stable it is captured and, for a two point
+/// calibration, the probe can be moved to the high reference (boiling water).
+pub struct Procedure {
+    reference_low : i32,
+    reference_high : Option<i32>,
+    measured_low : i32,
+    step : Step,
+
+    count : u32,
+    sum : i64,
+    min : i32,
+    max : i32,
+}
+
+impl Procedure {
+    /// Single point calibration against `reference`.
+    pub fn offset(reference: i32) -> Procedure {
+        Procedure::new(reference, None)
+    }
+
+    /// Two point calibration, `reference_high` should account for the
+    /// boiling point at the local air pressure.
+    pub fn two_point(reference_low: i32, reference_high: i32) -> Procedure {
+        Procedure::new(reference_low, Some(reference_high))
+    }
+
+    fn new(reference_low: i32, reference_high: Option<i32>) -> Procedure {
+        Procedure {
+            reference_low: reference_low,
+            reference_high: reference_high,
+            measured_low: 0,
+            step: Step::Low,
+            count: 0,
+            sum: 0,
+            min: 0,
+            max: 0,
+        }
+    }
+
+    pub fn step(&self) -> Step {
+        self.step
+    }
+
+    /// Feed an uncalibrated reading, returns the calibration once all
+    /// reference points have been captured.
+    pub fn sample(&mut self, temp: i32) -> Option<Calibration> {
+        let measured = match self.settle(temp) {
+            Some(measured) => measured,
+            None => return None
+        };
+
+        match (self.step, self.reference_high) {
+            (Step::Low, None) => {
+                self.step = Step::Done;
+                Some(Calibration::offset(measured, self.reference_low))
+            }
+            (Step::Low, Some(_)) => {
+                self.measured_low = measured;
+                self.step = Step::High;
+                None
+            }
+            (Step::High, Some(reference_high)) => {
+                // still settled at the low reference, wait for the probe to move
+                if (measured - self.measured_low) * 2 < reference_high - self.reference_low {
+                    return None;
+                }
+                self.step = Step::Done;
+                Calibration::two_point(self.measured_low, self.reference_low, measured, reference_high)
+            }
+            _ => None
+        }
+    }
+
+    /// Track the spread of the readings, returns their mean once
+    /// `STABLE_SAMPLES` readings stayed within `STABLE_TOLERANCE`.
+    fn settle(&mut self, temp: i32) -> Option<i32> {
+        if self.count == 0 || temp - self.min > STABLE_TOLERANCE || self.max - temp > STABLE_TOLERANCE {
+            self.count = 0;
+            self.sum = 0;
+            self.min = temp;
+            self.max = temp;
+        }
+
+        self.count += 1;
+        self.sum += temp as i64;
+        if temp < self.min { self.min = temp; }
+        if temp > self.max { self.max = temp; }
+
+        if self.count >= STABLE_SAMPLES {
+            let mean = (self.sum / self.count as i64) as i32;
+            self.count = 0;
+            Some(mean)
+        } else {
+            None
+        }
+    }
+}
\ No newline at end of file
@@ -44,6 +44,21 @@ impl<'a, T: 'a + Copy> CyclicBuffer<'a, T> {
         self.len == 0
     }
 
+    #[inline(always)]
+    pub fn full(&self) -> bool {
+        self.len >= self.data.len()
+    }
+
+    /// Element `i` counted from the oldest one.
+    #[inline(always)]
+    pub fn get(&self, i: usize) -> Option<T> {
+        if i >= self.len {
+            None
+        } else {
+            Some(self.data[(self.ptr + i) % self.data.len()])
+        }
+    }
+
     #[inline(never)]
     pub fn length(&self) -> usize {
         self.len
@@ -0,0 +1,281 @@
+#[macro_use]
+#[allow(unused_imports)]
+use debug;
+
+use onewire::{self, OneWire, Rom, crc8};
+use calibration::Calibration;
+use sensor::{TemperatureSensor, Temperature, Measurement, Reading, SensorError, Status};
+
+pub const FAMILY : u8 = 0x28;
+
+const CMD_CONVERT_T : u8 = 0x44;
+const CMD_WRITE_SCRATCHPAD : u8 = 0x4E;
+const CMD_READ_SCRATCHPAD : u8 = 0xBE;
+
+/// Maximum number of devices on the bus.
+pub const MAX_DEVICES : usize = 3;
+
+/// Fault code shown if a device does not answer.
+pub const FAULT_NO_PRESENCE : u8 = 1;
+
+/// Temperature register after power on, 85 degrees.
+const POWER_ON_RAW : i16 = 0x0550;
+/// 85 degrees is only believed if the previous reading was this close to it,
+/// in 1/100 degrees.
+const POWER_ON_DELTA : i32 = 500;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Bits9 = 0,
+    Bits10 = 1,
+    Bits11 = 2,
+    Bits12 = 3,
+}
+
+impl Resolution {
+    /// Maximum conversion time in TIM2 ticks.
+    pub fn conversion_ticks(&self) -> u64 {
+        match *self {
+            Resolution::Bits9 => 94,
+            Resolution::Bits10 => 188,
+            Resolution::Bits11 => 375,
+            Resolution::Bits12 => 750,
+        }
+    }
+
+    fn config(&self) -> u8 {
+        (*self as u8) << 5 | 0x1F
+    }
+
+    /// Mask of the temperature bits which are defined at this resolution.
+    fn mask(&self) -> i16 {
+        !((1 << (3 - *self as u8)) - 1)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    /// Waiting for the next conversion
+    Idle,
+    /// Start the conversion of all devices at once
+    Convert(u8),
+    Converting(u64),
+    Reset(usize),
+    /// Send byte `n` of match ROM and read scratchpad
+    Command(usize, usize),
+    /// Read byte `n` of the scratchpad
+    Read(usize, usize),
+}
+
+/// DS18B20 sensors on one 1-Wire bus, converting together every `interval`
+/// ticks. The bus is driven one step per call of `tick` so a whole reading
+/// never blocks the timer interrupt for more than a byte.
+pub struct Ds18b20s {
+    bus : OneWire,
+    roms : [Rom; MAX_DEVICES],
+    count : usize,
+    first_channel : u8,
+    resolution : Resolution,
+    interval : u64,
+
+    step : Step,
+    next_start : u64,
+    scratchpad : [u8; 9],
+
+    calibration : [Calibration; MAX_DEVICES],
+    status : [Status; MAX_DEVICES],
+    readings : [Option<Reading>; MAX_DEVICES],
+    /// Last valid uncalibrated temperature of each device
+    last : [Option<i32>; MAX_DEVICES],
+}
+
+impl Ds18b20s {
+    /// Search the bus for DS18B20s and configure their resolution. The
+    /// channels of the sensors are numbered from `first_channel`.
+    pub fn new(bus: OneWire, resolution: Resolution, interval: u64, first_channel: u8) -> Ds18b20s {
+        let mut roms = [Rom([0; 8]); MAX_DEVICES];
+        let count = bus.search(Some(FAMILY), &mut roms);
+
+        if bus.reset() {
+            // high and low alarm thresholds are unused
+            bus.write_byte(onewire::CMD_SKIP_ROM);
+            bus.write_byte(CMD_WRITE_SCRATCHPAD);
+            bus.write_byte(0x7F);
+            bus.write_byte(0x80);
+            bus.write_byte(resolution.config());
+        }
+
+        let mut status = [Status::Stopped; MAX_DEVICES];
+        for s in status.iter_mut().take(count) {
+            *s = Status::Running;
+        }
+
+        Ds18b20s {
+            bus: bus,
+            roms: roms,
+            count: count,
+            first_channel: first_channel,
+            resolution: resolution,
+            interval: interval,
+            step: Step::Idle,
+            next_start: 0,
+            scratchpad: [0; 9],
+            calibration: [Calibration::None; MAX_DEVICES],
+            status: status,
+            readings: [None; MAX_DEVICES],
+            last: [None; MAX_DEVICES],
+        }
+    }
+
+    pub fn rom(&self, device: usize) -> Option<Rom> {
+        if device < self.count { Some(self.roms[device]) } else { None }
+    }
+
+    /// Run the next step on the bus, call once every TIM2 tick.
+    pub fn tick(&mut self, now: u64) {
+        if self.count == 0 {
+            return;
+        }
+
+        let step = self.step;
+        self.step = match step {
+            Step::Idle => {
+                if now < self.next_start {
+                    return;
+                }
+                self.next_start = now + self.interval;
+                if self.bus.reset() {
+                    Step::Convert(0)
+                } else {
+                    for device in 0..self.count {
+                        self.report(now, device, Err(SensorError::Fault(FAULT_NO_PRESENCE)));
+                    }
+                    Step::Idle
+                }
+            }
+            Step::Convert(0) => {
+                self.bus.write_byte(onewire::CMD_SKIP_ROM);
+                Step::Convert(1)
+            }
+            Step::Convert(_) => {
+                self.bus.write_byte(CMD_CONVERT_T);
+                Step::Converting(now + self.resolution.conversion_ticks())
+            }
+            Step::Converting(done) => {
+                if now < done {
+                    return;
+                }
+                Step::Reset(0)
+            }
+            Step::Reset(device) => {
+                if self.bus.reset() {
+                    Step::Command(device, 0)
+                } else {
+                    self.report(now, device, Err(SensorError::Fault(FAULT_NO_PRESENCE)));
+                    self.next_device(device)
+                }
+            }
+            Step::Command(device, n) => {
+                let byte = match n {
+                    0 => onewire::CMD_MATCH_ROM,
+                    1...8 => self.roms[device].0[n - 1],
+                    _ => CMD_READ_SCRATCHPAD,
+                };
+                self.bus.write_byte(byte);
+                if n < 9 { Step::Command(device, n + 1) } else { Step::Read(device, 0) }
+            }
+            Step::Read(device, n) => {
+                self.scratchpad[n] = self.bus.read_byte();
+                if n + 1 < self.scratchpad.len() {
+                    Step::Read(device, n + 1)
+                } else {
+                    let result = self.temperature(device);
+                    self.report(now, device, result);
+                    self.next_device(device)
+                }
+            }
+        };
+    }
+
+    /// Index of the device reporting as `channel`, if it is one of ours.
+    fn device(&self, channel: u8) -> Option<usize> {
+        if channel < self.first_channel {
+            return None;
+        }
+        let device = (channel - self.first_channel) as usize;
+        if device < self.count { Some(device) } else { None }
+    }
+
+    fn next_device(&self, device: usize) -> Step {
+        if device + 1 < self.count { Step::Reset(device + 1) } else { Step::Idle }
+    }
+
+    /// Temperature in 1/100 degrees from the scratchpad just read.
+    ///
+    /// A bus stuck low reads as all zeros, which passes the CRC. The power on
+    /// value of 85 degrees means the device reset and has not converted yet,
+    /// unless the temperature was already close to it.
+    fn temperature(&self, device: usize) -> Result<i32, SensorError> {
+        if self.scratchpad.iter().all(|b| *b == 0) || crc8(&self.scratchpad) != 0 {
+            return Err(SensorError::Implausible);
+        }
+        // 1/16 degrees, the lowest bits are undefined at lower resolutions
+        let raw = ((self.scratchpad[1] as u16) << 8 | self.scratchpad[0] as u16) as i16;
+        let temp = (raw & self.resolution.mask()) as i32 * 25 / 4;
+
+        if raw == POWER_ON_RAW {
+            match self.last[device] {
+                Some(last) if (last - temp).abs() <= POWER_ON_DELTA => {}
+                _ => return Err(SensorError::Implausible)
+            }
+        }
+        Ok(temp)
+    }
+
+    fn report(&mut self, now: u64, device: usize, result: Result<i32, SensorError>) {
+        self.last[device] = result.ok();
+        let calibration = self.calibration[device];
+        let value = result.map(|temp| Measurement {
+            temperature: Temperature(calibration.apply(temp)),
+            uncalibrated: Temperature(temp),
+        });
+
+        self.status[device] = match value {
+            Ok(_) => Status::Running,
+            Err(e) => Status::Failed(e),
+        };
+        self.readings[device] = Some(Reading {
+            channel: self.first_channel + device as u8,
+            time: now,
+            value: value,
+        });
+    }
+}
+
+impl TemperatureSensor for Ds18b20s {
+    fn channels(&self) -> usize {
+        self.count
+    }
+
+    fn status(&self, channel: u8) -> Status {
+        match self.device(channel) {
+            Some(device) => self.status[device],
+            None => Status::Stopped
+        }
+    }
+
+    fn take(&mut self) -> Option<Reading> {
+        for reading in self.readings.iter_mut() {
+            if reading.is_some() {
+                return reading.take();
+            }
+        }
+        None
+    }
+
+    fn set_calibration(&mut self, channel: u8, calibration: Calibration) {
+        if let Some(device) = self.device(channel) {
+            self.calibration[device] = calibration;
+        }
+    }
+}
\ No newline at end of file
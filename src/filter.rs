@@ -0,0 +1,126 @@
+use cyclicbuffer::CyclicBuffer;
+
+/// Maximum window of the median filter.
+pub const MAX_WINDOW : usize = 16;
+/// Weight of one in `Kind::Exponential`.
+pub const ALPHA_ONE : i32 = 256;
+/// Consecutive spikes after which the sample is taken as a real step change.
+pub const MAX_SPIKES : u8 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Passes samples unchanged
+    None,
+    /// Mean of the window
+    MovingAverage,
+    /// Median of the window, at most `MAX_WINDOW` samples are used
+    Median,
+    /// Exponential moving average, weight of a new sample in `1/ALPHA_ONE`
+    Exponential(i32),
+}
+
+/// Filter stage for temperatures in 1/100 degrees, keeping the last samples
+/// in the window passed to `new`.
+pub struct Filter<'a> {
+    kind : Kind,
+    window : CyclicBuffer<'a, i32>,
+    sum : i32,
+    /// Exponential average scaled by `ALPHA_ONE`
+    average : i32,
+    value : Option<i32>,
+    /// Maximum difference of a sample from the filtered value
+    spike : Option<i32>,
+    spikes : u8,
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(kind: Kind, window: &'a mut [i32], spike: Option<i32>) -> Filter<'a> {
+        Filter {
+            kind: kind,
+            window: CyclicBuffer { data: window, ptr: 0, len: 0 },
+            sum: 0,
+            average: 0,
+            value: None,
+            spike: spike,
+            spikes: 0,
+        }
+    }
+
+    /// The current filtered value.
+    pub fn value(&self) -> Option<i32> {
+        self.value
+    }
+
+    /// Drop all samples, e.g. after a fault.
+    pub fn reset(&mut self) {
+        while let Some(_) = self.window.read() {}
+        self.sum = 0;
+        self.value = None;
+        self.spikes = 0;
+    }
+
+    /// Add a sample, returns the new filtered value or `None` if the sample
+    /// was rejected as a spike.
+    pub fn add(&mut self, sample: i32) -> Option<i32> {
+        if let (Some(value), Some(spike)) = (self.value, self.spike) {
+            if (sample - value).abs() > spike {
+                self.spikes += 1;
+                if self.spikes < MAX_SPIKES {
+                    return None;
+                }
+                // the temperature really moved, start over from here
+                self.reset();
+            }
+        }
+        self.spikes = 0;
+
+        if self.window.full() {
+            if let Some(old) = self.window.read() {
+                self.sum -= old;
+            }
+        }
+        self.window.write(sample);
+        self.sum += sample;
+
+        let value = match self.kind {
+            Kind::None => sample,
+            Kind::MovingAverage => self.sum / self.window.length() as i32,
+            Kind::Median => self.median(),
+            Kind::Exponential(alpha) => {
+                if self.value.is_none() {
+                    self.average = sample * ALPHA_ONE;
+                } else {
+                    let delta = sample * ALPHA_ONE - self.average;
+                    self.average += (delta as i64 * alpha as i64 / ALPHA_ONE as i64) as i32;
+                }
+                self.average / ALPHA_ONE
+            }
+        };
+
+        self.value = Some(value);
+        Some(value)
+    }
+
+    fn median(&self) -> i32 {
+        let mut sorted = [0i32; MAX_WINDOW];
+        let len = if self.window.length() < MAX_WINDOW { self.window.length() } else { MAX_WINDOW };
+
+        // insertion sort of the newest samples
+        let skip = self.window.length() - len;
+        for i in 0..len {
+            let sample = self.window.get(skip + i).unwrap_or(0);
+            let mut j = i;
+            while j > 0 && sorted[j - 1] > sample {
+                sorted[j] = sorted[j - 1];
+                j -= 1;
+            }
+            sorted[j] = sample;
+        }
+
+        if len % 2 == 1 {
+            sorted[len / 2]
+        } else {
+            (sorted[len / 2 - 1] + sorted[len / 2]) / 2
+        }
+    }
+}
\ No newline at end of file
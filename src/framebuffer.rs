@@ -0,0 +1,162 @@
+#[macro_use]
+#[allow(unused_imports)]
+use debug;
+use stm32;
+use screen;
+use ssd1306;
+
+use rtfm::{Resource, Threshold};
+
+/// Size of the largest supported panel, smaller ones use part of it.
+pub const MAX_WIDTH : usize = 128;
+pub const MAX_PAGES : usize = 8;
+
+/// Columns sent per write, so a flush doesn't take the whole I2C queue.
+const CHUNK : usize = 32;
+
+/// Copy of the display RAM, one byte per column and page with the top pixel
+/// in the highest bit, like the glyphs of `ssd1306`. Drawing only changes
+/// the copy and marks the touched columns of each page dirty, `flush` sends
+/// them to the display. The size follows the geometry the display was
+/// initialised with.
+pub struct Framebuffer {
+    data : [[u8; MAX_WIDTH]; MAX_PAGES],
+    /// First and last dirty column of each page
+    dirty : [Option<(u8, u8)>; MAX_PAGES],
+}
+
+pub static mut FRAMEBUFFER : Framebuffer = Framebuffer {
+    data: [[0; MAX_WIDTH]; MAX_PAGES],
+    dirty: [None; MAX_PAGES],
+};
+
+impl Framebuffer {
+    pub fn width(&self) -> usize {
+        ssd1306::geometry().width() as usize
+    }
+
+    pub fn height(&self) -> usize {
+        ssd1306::geometry().height() as usize
+    }
+
+    pub fn pages(&self) -> usize {
+        ssd1306::geometry().pages() as usize
+    }
+
+    fn mark(&mut self, page: usize, start: usize, end: usize) {
+        let (start, end) = (start as u8, end as u8);
+        self.dirty[page] = match self.dirty[page] {
+            Some((s, e)) => Some((if start < s { start } else { s }, if end > e { end } else { e })),
+            None => Some((start, end))
+        };
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.iter().any(|d| d.is_some())
+    }
+
+    /// Mark the whole display for sending, e.g. after it was cleared directly.
+    pub fn invalidate(&mut self) {
+        let width = self.width();
+        for page in 0..self.pages() {
+            self.mark(page, 0, width - 1);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        let width = self.width();
+        for page in 0..self.pages() {
+            if self.data[page].iter().any(|c| *c != 0) {
+                self.data[page] = [0; MAX_WIDTH];
+                self.mark(page, 0, width - 1);
+            }
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        if x >= self.width() || y >= self.height() {
+            return false;
+        }
+        self.data[y / 8][x] & (0x80 >> (y % 8)) != 0
+    }
+
+    /// Set or clear a pixel, pixels outside of the panel are ignored.
+    pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let page = y / 8;
+        let old = self.data[page][x];
+        let bit = 0x80 >> (y % 8);
+        let new = if on { old | bit } else { old & !bit };
+        if new != old {
+            self.data[page][x] = new;
+            self.mark(page, x, x);
+        }
+    }
+
+    /// Set or clear all pixels of a rectangle.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, on: bool) {
+        for py in y..y + height {
+            for px in x..x + width {
+                self.set_pixel(px, py, on);
+            }
+        }
+    }
+
+    /// Column byte of `page`.
+    pub fn column(&self, page: usize, column: usize) -> u8 {
+        if page >= self.pages() || column >= self.width() {
+            return 0;
+        }
+        self.data[page][column]
+    }
+
+    /// Overwrite columns of `page` starting at `column` with bytes in the
+    /// format of `ssd1306::NUMBERS`, clipped at the right edge. Returns the
+    /// column after the last one written.
+    pub fn write_columns(&mut self, page: usize, column: usize, columns: &[u8]) -> usize {
+        let width = self.width();
+        if page >= self.pages() || column >= width {
+            return column + columns.len();
+        }
+
+        let mut first = None;
+        let mut last = 0;
+        for (i, c) in columns.iter().take(width - column).enumerate() {
+            let x = column + i;
+            if self.data[page][x] != *c {
+                self.data[page][x] = *c;
+                if first.is_none() {
+                    first = Some(x);
+                }
+                last = x;
+            }
+        }
+        if let Some(first) = first {
+            self.mark(page, first, last);
+        }
+        column + columns.len()
+    }
+
+    /// Send the dirty regions to the display. Regions which did not fit into
+    /// the I2C queue stay dirty for the next flush.
+    pub fn flush<'a, S>(&mut self, t: &mut Threshold, i2c1: &'a S)
+    where
+        S : Resource<Data = stm32::I2C1>
+    {
+        for page in 0..self.pages() {
+            while let Some((start, end)) = self.dirty[page] {
+                let (start, end) = (start as usize, end as usize);
+                let stop = if end - start + 1 > CHUNK { start + CHUNK - 1 } else { end };
+
+                if !screen::set_address(t, i2c1, start as u8, page as u8) ||
+                    !ssd1306::write_data(t, i2c1, &self.data[page][start..stop + 1]) {
+                    return;
+                }
+
+                self.dirty[page] = if stop == end { None } else { Some((stop as u8 + 1, end as u8)) };
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,380 @@
+#[macro_use]
+#[allow(unused_imports)]
+use debug;
+use stm32::I2C1;
+use cortex_m::peripheral::SCB;
+
+use rtfm::{Resource, Threshold};
+use cyclicbuffer::CyclicBuffer;
+
+const CR1_START : u32 = 1 << 8;
+const CR1_STOP : u32 = 1 << 9;
+const CR1_ACK : u32 = 1 << 10;
+const CR1_POS : u32 = 1 << 11;
+
+const CR2_ITERREN : u32 = 1 << 8;
+const CR2_ITEVTEN : u32 = 1 << 9;
+const CR2_ITBUFEN : u32 = 1 << 10;
+
+const SR1_SB : u32 = 1 << 0;
+const SR1_ADDR : u32 = 1 << 1;
+const SR1_BTF : u32 = 1 << 2;
+const SR1_RXNE : u32 = 1 << 6;
+const SR1_TXE : u32 = 1 << 7;
+const SR1_BERR : u32 = 1 << 8;
+const SR1_ARLO : u32 = 1 << 9;
+const SR1_AF : u32 = 1 << 10;
+const SR1_OVR : u32 = 1 << 11;
+
+/// Maximum number of bytes of a read transaction.
+pub const MAX_READ : usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    Write,
+    Read,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The device did not acknowledge
+    Nack,
+    /// Bus error or arbitration lost
+    Bus,
+}
+
+/// Called from the I2C interrupts once a transaction finished, with the bytes
+/// read for read transactions.
+pub type Callback = fn(Result<&[u8], Error>);
+
+/// An entry of the queue, the payload of writes is kept in `DATA`.
+#[derive(Clone, Copy)]
+pub struct Transaction {
+    pub address : u8,
+    pub direction : Direction,
+    pub len : u8,
+    pub callback : Option<Callback>,
+}
+
+const EMPTY : Transaction = Transaction {
+    address: 0,
+    direction: Direction::Write,
+    len: 0,
+    callback: None,
+};
+
+const QUEUE_LEN : usize = 32;
+static mut _QUEUE : [Transaction; QUEUE_LEN] = [EMPTY; QUEUE_LEN];
+static mut QUEUE : CyclicBuffer<Transaction> = unsafe { CyclicBuffer { data: &mut _QUEUE, ptr: 0, len: 0} };
+
+const DATA_LEN : usize = 512;
+static mut _DATA : [u8; DATA_LEN] = [0; DATA_LEN];
+static mut DATA : CyclicBuffer<u8> = unsafe { CyclicBuffer { data: &mut _DATA, ptr: 0, len: 0} };
+
+#[derive(Clone, Copy)]
+enum State {
+    Idle,
+    /// Waiting for the start condition
+    Start,
+    Address,
+    /// Transferring the given byte of the current transaction
+    Transfer(u8),
+}
+
+static mut STATE : State = State::Idle;
+static mut CURRENT : Transaction = EMPTY;
+static mut READ_BUFFER : [u8; MAX_READ] = [0; MAX_READ];
+
+/// Free space for payloads in the queue.
+pub fn space() -> usize {
+    unsafe { DATA_LEN - DATA.length() }
+}
+
+/// Wait until at least half of the queue and payload buffer are free again.
+/// Only call this from the idle loop, the I2C interrupts must be able to
+/// preempt it.
+#[inline(never)]
+pub fn wait_space() {
+    while space() < DATA_LEN / 2 || unsafe { QUEUE.length() } > QUEUE_LEN / 2 {
+        ::rtfm::wfi();
+    }
+}
+
+/// True while running an exception or interrupt handler rather than idle.
+fn in_interrupt() -> bool {
+    let scb = unsafe { &*SCB::ptr() };
+    scb.icsr.read() & 0x1FF != 0
+}
+
+/// Add transactions using `push` and start the bus. Without room the idle
+/// loop waits for the interrupts to drain the queue, a task can't as it
+/// would block them, so it gets false back.
+fn enqueue<S, F>(t: &mut Threshold, i2c: &S, push: F) -> bool
+where
+    S : Resource<Data = I2C1>,
+    F : Fn() -> bool
+{
+    loop {
+        let queued = i2c.claim(t, |i2c, _t| {
+            let queued = push();
+            if queued {
+                unsafe { kick(i2c); }
+            }
+            queued
+        });
+
+        if queued || in_interrupt() {
+            return queued;
+        }
+        ::rtfm::wfi();
+    }
+}
+
+/// Queue writing `prefix` followed by `data` to the device at `address`.
+/// Returns false if it doesn't fit into the queue.
+pub fn write<S>(t: &mut Threshold, i2c: &S, address: u8, prefix: &[u8], data: &[u8], callback: Option<Callback>) -> bool
+where
+    S : Resource<Data = I2C1>
+{
+    let len = prefix.len() + data.len();
+    if len > 255 || len > DATA_LEN {
+        return false;
+    }
+
+    enqueue(t, i2c, || unsafe {
+        if QUEUE.full() || space() < len {
+            return false;
+        }
+
+        for b in prefix.iter().chain(data.iter()) {
+            DATA.write(*b);
+        }
+        QUEUE.write(Transaction {
+            address: address,
+            direction: Direction::Write,
+            len: len as u8,
+            callback: callback,
+        });
+        true
+    })
+}
+
+/// Queue reading `len` bytes from the device at `address`.
+pub fn read<S>(t: &mut Threshold, i2c: &S, address: u8, len: usize, callback: Callback) -> bool
+where
+    S : Resource<Data = I2C1>
+{
+    if len == 0 || len > MAX_READ {
+        return false;
+    }
+
+    enqueue(t, i2c, || unsafe {
+        if QUEUE.full() {
+            return false;
+        }
+
+        QUEUE.write(Transaction {
+            address: address,
+            direction: Direction::Read,
+            len: len as u8,
+            callback: Some(callback),
+        });
+        true
+    })
+}
+
+/// Queue writing `data`, e.g. a register address, followed by reading `len`
+/// bytes. No other transaction is put in between.
+pub fn write_read<S>(t: &mut Threshold, i2c: &S, address: u8, data: &[u8], len: usize, callback: Callback) -> bool
+where
+    S : Resource<Data = I2C1>
+{
+    if len == 0 || len > MAX_READ || data.len() > 255 {
+        return false;
+    }
+
+    enqueue(t, i2c, || unsafe {
+        if QUEUE.length() + 2 > QUEUE_LEN || space() < data.len() {
+            return false;
+        }
+
+        for b in data.iter() {
+            DATA.write(*b);
+        }
+        QUEUE.write(Transaction {
+            address: address,
+            direction: Direction::Write,
+            len: data.len() as u8,
+            callback: None,
+        });
+        QUEUE.write(Transaction {
+            address: address,
+            direction: Direction::Read,
+            len: len as u8,
+            callback: Some(callback),
+        });
+        true
+    })
+}
+
+/// Start the next transaction unless the bus is busy.
+unsafe fn kick(i2c: &I2C1) {
+    if let State::Idle = STATE {
+        start_next(i2c);
+    }
+}
+
+unsafe fn start_next(i2c: &I2C1) {
+    match QUEUE.read() {
+        Some(transaction) => {
+            CURRENT = transaction;
+            STATE = State::Start;
+
+            // a stop condition of the previous transaction has to be sent first
+            while i2c.cr1.read().bits() & CR1_STOP != 0 { }
+
+            i2c.cr2.modify(|r, w| w.bits(r.bits() | CR2_ITEVTEN | CR2_ITBUFEN | CR2_ITERREN));
+            i2c.cr1.modify(|r, w| w.bits(r.bits() | CR1_START));
+        }
+        None => {
+            STATE = State::Idle;
+            i2c.cr2.modify(|r, w| w.bits(r.bits() & !(CR2_ITEVTEN | CR2_ITBUFEN)));
+        }
+    }
+}
+
+/// Finish the current transaction and continue with the next one.
+unsafe fn finish(i2c: &I2C1, result: Result<(), Error>) {
+    let current = CURRENT;
+
+    // drop what is left of the payload of a failed write
+    if current.direction == Direction::Write {
+        let sent = match STATE {
+            State::Transfer(pos) => pos,
+            _ => 0
+        };
+        for _ in sent..current.len {
+            DATA.read();
+        }
+    }
+
+    if let Some(callback) = current.callback {
+        match (result, current.direction) {
+            (Ok(_), Direction::Read) => callback(Ok(&READ_BUFFER[..current.len as usize])),
+            (Ok(_), Direction::Write) => callback(Ok(&[])),
+            (Err(e), _) => callback(Err(e)),
+        }
+    }
+
+    start_next(i2c);
+}
+
+/// Handle the I2C1 event interrupt.
+pub fn event_interrupt(i2c: &I2C1) {
+    unsafe {
+        let sr1 = i2c.sr1.read().bits();
+        let read = CURRENT.direction == Direction::Read;
+        let len = CURRENT.len;
+        let state = STATE;
+
+        match state {
+            State::Start if sr1 & SR1_SB != 0 => {
+                let address = (CURRENT.address << 1) | if read { 1 } else { 0 };
+                i2c.dr.write(|w| w.bits(address as u32));
+                STATE = State::Address;
+            }
+            State::Address if sr1 & SR1_ADDR != 0 => {
+                // the ACK, POS and STOP sequences follow the reference manual
+                // for receptions of 1, 2 and more bytes, so that a late
+                // interrupt only stretches the clock
+                if read && len == 1 {
+                    // NACK and stop right after the only byte
+                    i2c.cr1.modify(|r, w| w.bits(r.bits() & !CR1_ACK));
+                    i2c.sr2.read();
+                    i2c.cr1.modify(|r, w| w.bits(r.bits() | CR1_STOP));
+                } else if read && len == 2 {
+                    // NACK the second byte, both are read once BTF is set
+                    i2c.cr1.modify(|r, w| w.bits((r.bits() & !CR1_ACK) | CR1_POS));
+                    i2c.sr2.read();
+                    i2c.cr2.modify(|r, w| w.bits(r.bits() & !CR2_ITBUFEN));
+                } else {
+                    if read {
+                        i2c.cr1.modify(|r, w| w.bits(r.bits() | CR1_ACK));
+                        if len == 3 {
+                            i2c.cr2.modify(|r, w| w.bits(r.bits() & !CR2_ITBUFEN));
+                        }
+                    }
+                    i2c.sr2.read();
+                }
+                STATE = State::Transfer(0);
+            }
+            State::Transfer(pos) if read => {
+                let remaining = len - pos;
+                if remaining == 1 && sr1 & SR1_RXNE != 0 {
+                    READ_BUFFER[pos as usize] = i2c.dr.read().bits() as u8;
+                    STATE = State::Transfer(len);
+                    finish(i2c, Ok(()));
+                } else if remaining == 2 && sr1 & SR1_BTF != 0 {
+                    // two byte reception, the second byte is in the shift register
+                    i2c.cr1.modify(|r, w| w.bits(r.bits() | CR1_STOP));
+                    READ_BUFFER[pos as usize] = i2c.dr.read().bits() as u8;
+                    READ_BUFFER[pos as usize + 1] = i2c.dr.read().bits() as u8;
+                    i2c.cr1.modify(|r, w| w.bits(r.bits() & !CR1_POS));
+                    STATE = State::Transfer(len);
+                    finish(i2c, Ok(()));
+                } else if remaining == 3 && sr1 & SR1_BTF != 0 {
+                    // byte N-2 in DR and N-1 in the shift register, NACK byte N
+                    i2c.cr1.modify(|r, w| w.bits(r.bits() & !CR1_ACK));
+                    READ_BUFFER[pos as usize] = i2c.dr.read().bits() as u8;
+                    i2c.cr1.modify(|r, w| w.bits(r.bits() | CR1_STOP));
+                    READ_BUFFER[pos as usize + 1] = i2c.dr.read().bits() as u8;
+                    STATE = State::Transfer(pos + 2);
+                    i2c.cr2.modify(|r, w| w.bits(r.bits() | CR2_ITBUFEN));
+                } else if remaining > 3 && sr1 & SR1_RXNE != 0 {
+                    READ_BUFFER[pos as usize] = i2c.dr.read().bits() as u8;
+                    STATE = State::Transfer(pos + 1);
+                    if remaining == 4 {
+                        // wait for BTF to handle the last three bytes
+                        i2c.cr2.modify(|r, w| w.bits(r.bits() & !CR2_ITBUFEN));
+                    }
+                }
+            }
+            State::Transfer(pos) if !read && sr1 & (SR1_TXE | SR1_BTF) != 0 => {
+                if pos < len {
+                    let b = DATA.read().unwrap_or(0);
+                    i2c.dr.write(|w| w.bits(b as u32));
+                    STATE = State::Transfer(pos + 1);
+                    if pos + 1 == len {
+                        // wait for BTF of the last byte
+                        i2c.cr2.modify(|r, w| w.bits(r.bits() & !CR2_ITBUFEN));
+                    }
+                } else if sr1 & SR1_BTF != 0 {
+                    i2c.cr1.modify(|r, w| w.bits(r.bits() | CR1_STOP));
+                    finish(i2c, Ok(()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Handle the I2C1 error interrupt, the current transaction fails.
+pub fn error_interrupt(i2c: &I2C1) {
+    unsafe {
+        let sr1 = i2c.sr1.read().bits();
+        let errors = sr1 & (SR1_BERR | SR1_ARLO | SR1_AF | SR1_OVR);
+        i2c.sr1.modify(|r, w| w.bits(r.bits() & !errors));
+
+        if let State::Idle = STATE {
+            return;
+        }
+
+        let error = if sr1 & SR1_AF != 0 { Error::Nack } else { Error::Bus };
+        i2c.cr1.modify(|r, w| w.bits(r.bits() & !CR1_POS));
+        // arbitration lost releases the bus by itself
+        if sr1 & SR1_ARLO == 0 {
+            i2c.cr1.modify(|r, w| w.bits(r.bits() | CR1_STOP));
+        }
+        finish(i2c, Err(error));
+    }
+}
\ No newline at end of file
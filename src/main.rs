@@ -19,15 +19,36 @@ pub mod tempsensor;
 pub mod bh1750;
 pub mod ssd1306;
 pub mod temp_conversion;
-
-use tslib::{rcc, afio, spi, gpio, i2c};
+pub mod calibration;
+pub mod filter;
+pub mod rate;
+pub mod sensor;
+pub mod thermocouple;
+pub mod onewire;
+pub mod ds18b20;
+pub mod watchdog;
+pub mod i2cbus;
+pub mod framebuffer;
+
+use tslib::{rcc, afio, spi, gpio};
 
 use rcc::{Rcc};
 use afio::Afio;
 use gpio::{Gpio};
 use spi::{Spi};
-use i2c::{I2c};
-use tempsensor::{SPI_RES, SpiState};
+use tempsensor::{SPI_RES, SpiState, Config, Wires, Filter};
+use tempsensor::{Max31865, Unconfigured, Alarm, DrdyLine, Sampling, Scheduler};
+use tempsensor::{ChipSelect, Probes, ProbeType, MAX_PROBES};
+use thermocouple::{Device, Max31855, Max31856};
+use onewire::OneWire;
+use ds18b20::{Ds18b20s, Resolution};
+use watchdog::WATCHDOG;
+use framebuffer::FRAMEBUFFER;
+use bh1750::Bh1750;
+use calibration::{Calibration, Procedure};
+use rate::Rate;
+use sensor::{TemperatureSensor, Temperature, SensorError};
+use temp_conversion::Unit;
 
 use stm32::{GPIOA, I2C1, EXTI, SPI2 as SPI2_reg, TIM2 as TIM2_R};
 
@@ -61,7 +82,7 @@ app! {
         SPI2: {
             path: spi_interrupt,
             priority: 1,
-            resources: [I2C1, SPI2_REG]
+            resources: [SPI2_REG]
         },
         EXTI9_5: {
             path: external_interrupt,
@@ -71,7 +92,7 @@ app! {
         TIM2: {
             path: timer2_interrupt,
             priority: 1,
-            resources: [I2C1, COUNTER, TIM2_R]
+            resources: [I2C1, SPI2_REG, COUNTER, TIM2_R]
         }
     },
 }
@@ -102,24 +123,32 @@ fn init(p: init::Peripherals, _r : init::Resources) -> init::LateResources {
     let afio = Afio(&p.device.AFIO);
     let afio_periph = afio.get_peripherals();
 
-    // initialize the temperature sensor
-    tempsensor::init_temp(&p.device.SPI2, pinsb.12, pinsb.13, pinsb.14, pinsb.15);
+    // initialize the temperature sensors, they share the bus using software
+    // chip selects
+    tempsensor::init_spi(&p.device.SPI2, pinsb.12, pinsb.13, pinsb.14, pinsb.15);
+    tempsensor::use_software_nss(&p.device.SPI2);
+
+    for (i, &(cs, drdy, probe)) in PROBE_PINS.iter().enumerate() {
+        let cs = ChipSelect::new(&p.device.GPIOB, cs);
+        unsafe { MAX31865[i] = Some(Max31865::with_chip_select(cs, probe)); }
+
+        // initialize the external interrupt of the DRDY line
+        drdy.enable(&p.device.AFIO, &p.device.EXTI);
+    }
+
+    for (i, &(cs, _)) in THERMOCOUPLE_PINS.iter().enumerate() {
+        unsafe { THERMOCOUPLE_CS[i] = Some(ChipSelect::new(&p.device.GPIOB, cs)); }
+    }
+
+    if let Some(pin) = ONEWIRE_PIN {
+        unsafe { ONEWIRE = Some(OneWire::new(&p.device.GPIOB, pin)); }
+    }
 
     // initialize the screen
-    screen::init_screen(&p.device.I2C1, pinsb.8, pinsb.9, afio_periph.i2c1);
+    screen::init_screen(&p.device.I2C1, pinsb.8, pinsb.9, afio_periph.i2c1, PANEL);
     
     iprintln!("Finished initialization");
 
-    // initialize external timer interrupt
-    unsafe {
-        // set line 5 to A0
-        p.device.AFIO.exticr3.modify(|_, w| w.exti8().bits(0));
-        // enable interrupt on line 8
-        p.device.EXTI.imr.modify(|_, w| w.mr8().set_bit());
-        // enable fall trigger on line 8
-        p.device.EXTI.ftsr.modify(|_, w| w.tr8().set_bit());
-    }
-
     let tim2 = rcc_periph.tim2.enable_tim2().reset();
 
 
@@ -128,8 +157,9 @@ fn init(p: init::Peripherals, _r : init::Resources) -> init::LateResources {
         let tim = &p.device.TIM2;
         // set prescaler to f_apb / 800
         tim.psc.modify(|_, w| w.psc().bits(799));
-        // set auto reset register to 10
-        tim.arr.modify(|_, w| w.arr().bits(10));
+        // update every 10 counts, a 1 ms tick at 8 MHz which the intervals,
+        // deadlines and `rate::TICKS_PER_MINUTE` are based on
+        tim.arr.modify(|_, w| w.arr().bits(9));
         // enable interrupt
         tim.dier.modify(|_, w| w.uie().set_bit());
         // enable counter
@@ -148,22 +178,118 @@ fn init(p: init::Peripherals, _r : init::Resources) -> init::LateResources {
 
 fn idle(t: &mut Threshold, r: idle::Resources) -> ! {
     screen::set_address_mode(t, &r.I2C1);
-    screen::set_address(t, &r.I2C1, 0, 0);
 
+    // the display RAM is undefined after power on, the timer sends the
+    // whole framebuffer
+    unsafe { FRAMEBUFFER.invalidate(); }
+
+    let mut line = screen::Line::new();
+    screen::write_number(&mut line, 10);
+    screen::show(0, 0, &line);
+
+    let conf = Config::new()
+        .wires(Wires::Three)
+        .filter(Filter::Hz50);
+
+    if let Some(address) = LIGHT_SENSOR {
+        let mut bh1750 = Bh1750::new(address);
+        bh1750.set_auto_range(LIGHT_AUTO_RANGE);
+        i2cbus::wait_space();
+        let started = bh1750.power_on(t, &r.I2C1)
+            .and_then(|_| bh1750.start(unsafe { CNTR }, LIGHT_MODE, t, &r.I2C1));
+        match started {
+            Ok(_) => {
+                // the timer polls the sensor and updates the dimmer, it must
+                // not see half written values
+                r.TIM2_R.claim(t, |_tim2, _t| unsafe {
+                    BH1750 = Some(bh1750);
+                    DIMMER = Some(screen::Dimmer::new(&DIM_CURVE, DIM_HYSTERESIS));
+                });
+            },
+            Err(_) => { iprintln!("BH1750 commands could not be queued"); }
+        }
+    }
+
+    unsafe { WATCHDOG.on_stale(sensor_stale); }
 
-    for _i in 0..64 {
-        ssd1306::write_data(t, &r.I2C1, &[0; 8]);
-        ssd1306::wait_buffer();
-    } 
+    let mut probes = Probes::new();
+
+    for (i, &(_, drdy, _)) in PROBE_PINS.iter().enumerate() {
+        let max31865 = unsafe { MAX31865[i].take() }.unwrap();
+        let mut max31865 = match max31865.configure(t, &r.SPI2_REG, conf) {
+            Ok(max31865) => max31865,
+            Err(_) => {
+                iprintln!("MAX31865 {} configuration failed", i);
+                // nothing acknowledges the DRDY line of a probe which is not
+                // sampled, so an edge on it would fire EXTI9_5 forever
+                r.EXTI.claim(t, |exti, _t| drdy.disable(exti));
+                continue;
+            }
+        };
 
-    screen::set_address(t, &r.I2C1, 0, 0);
-    screen::write_number(t, &r.I2C1, 10);
+        // hardware enforced limits which trip even if the firmware stops polling
+        max31865.on_alarm(temperature_alarm);
+        if let Err(_) = max31865.set_thresholds(t, &r.SPI2_REG, UNIT.to_celsius(LOW_THRESHOLD), UNIT.to_celsius(HIGH_THRESHOLD)) {
+            iprintln!("MAX31865 {} threshold configuration failed", i);
+        }
+
+        // check the probe wiring before starting to convert
+        let status = match max31865.detect_faults(t, &r.SPI2_REG) {
+            Ok(status) => status,
+            Err(_) => {
+                iprintln!("MAX31865 {} fault detection did not complete", i);
+                r.EXTI.claim(t, |exti, _t| drdy.disable(exti));
+                continue;
+            }
+        };
+
+        let scheduler = Scheduler::start(max31865, SAMPLING, drdy, t, &r.SPI2_REG);
+        if let Some(probe) = probes.add(scheduler) {
+            setup_probe(&mut probes, probe.0, rtd_deadline());
+            probes.report_faults(unsafe { CNTR }, probe, status);
+        }
+    }
+
+    for (i, &(_, chip)) in THERMOCOUPLE_PINS.iter().enumerate() {
+        let cs = unsafe { THERMOCOUPLE_CS[i] }.unwrap();
+        let device = match chip {
+            thermocouple::Chip::Max31855 => Device::Max31855(Max31855::new(cs)),
+            thermocouple::Chip::Max31856(conf) => {
+                let max31856 = Max31856::new(cs);
+                if let Err(_) = max31856.configure(t, &r.SPI2_REG, conf) {
+                    iprintln!("MAX31856 {} configuration failed", i);
+                    continue;
+                }
+                Device::Max31856(max31856)
+            }
+        };
+
+        let scheduler = thermocouple::Scheduler::new(device, THERMOCOUPLE_INTERVAL);
+        if let Some(probe) = probes.add_thermocouple(scheduler) {
+            setup_probe(&mut probes, probe.0, THERMOCOUPLE_INTERVAL * STALE_INTERVALS);
+        }
+    }
+    show_readings(&mut probes);
+
+    // the DS18B20s take the channels after the SPI converters
+    if let Some(bus) = unsafe { ONEWIRE.take() } {
+        let first = probes.channels();
+        let mut ds18b20 = Ds18b20s::new(bus, DS18B20_RESOLUTION, DS18B20_INTERVAL, first as u8);
+        iprintln!("found {} DS18B20", ds18b20.channels());
+        for i in 0..ds18b20.channels() {
+            if first + i < MAX_PROBES {
+                setup_probe(&mut ds18b20, (first + i) as u8, DS18B20_INTERVAL * STALE_INTERVALS);
+            }
+        }
+        // the timer polls the DS18B20s, it must not see a half written value
+        r.TIM2_R.claim(t, |_tim2, _t| unsafe { DS18B20 = Some(ds18b20); });
+    }
 
     r.SPI2_REG.claim(t, |spi, _t| {
         let spi = Spi(&*spi);
         unsafe {
-           SPI_RES.start_write(0x80, 0b11010001, &spi);
-           //SPI_RES.start_read(0x0, &spi);
+            probes.poll(CNTR, &mut SPI_RES, &spi);
+            PROBES = Some(probes);
         }
     });
 
@@ -174,79 +300,306 @@ fn idle(t: &mut Threshold, r: idle::Resources) -> ! {
     }
 }
 
-pub enum ReadState {
-    Conf,
-    Lsb,
-    Msb(u8)
-}
-
 fn i2c_ev_interrupt(t: &mut Threshold, r: I2C1_EV::Resources) {
     let i2c = r.I2C1;
     i2c.claim(t, |i2c1, _t| {
-        let i2c = I2c(i2c1);
-        ssd1306::event_interrupt(&i2c);
+        i2cbus::event_interrupt(i2c1);
     });
 }
 
-fn i2c_er_interrupt(_t: &mut Threshold, r: I2C1_ER::Resources) {
-    let a = (r.I2C1).sr1.read();
+fn i2c_er_interrupt(t: &mut Threshold, r: I2C1_ER::Resources) {
+    let i2c = r.I2C1;
+    i2c.claim(t, |i2c1, _t| {
+        let a = i2c1.sr1.read();
+        iprintln!("er {} / AF {}", a.bits(), a.af().bit_is_set());
+        i2cbus::error_interrupt(i2c1);
+    });
+}
 
-    iprintln!("er {} / AF {}", a.bits(), a.af().bit_is_set());
-    rtfm::bkpt();
+/// Size of the SSD1306 panel
+const PANEL : ssd1306::Geometry = ssd1306::Geometry::W128H32;
+
+/// Unit of the displayed temperatures and of the thresholds and reference
+/// temperatures below
+const UNIT : Unit = Unit::Celsius;
+
+/// Fault thresholds of the RTD in 1/100 of `UNIT`
+const LOW_THRESHOLD : i32 = -1000;
+const HIGH_THRESHOLD : i32 = 10500;
+
+/// Chip select pin on port B, DRDY line and probe type of each converter, at
+/// most `MAX_PROBES`. The DRDY lines have to be within 5 to 9.
+const PROBE_PINS : [(u8, DrdyLine, ProbeType); 1] = [
+    (12, DrdyLine { port: 0, line: 8 }, tempsensor::PT100),
+];
+const SAMPLING : Sampling = Sampling::Continuous;
+
+/// Chip select pin on port B and converter of each thermocouple, they share
+/// the `MAX_PROBES` channels with the RTDs.
+const THERMOCOUPLE_PINS : [(u8, thermocouple::Chip); 0] = [];
+/// Ticks between reads of the thermocouple converters
+const THERMOCOUPLE_INTERVAL : u64 = 250;
+
+/// Address of the BH1750 ambient light sensor, `None` if it is not fitted
+const LIGHT_SENSOR : Option<u8> = None;
+const LIGHT_MODE : bh1750::Mode = bh1750::Mode::ContinuousH;
+/// Adjust the sensitivity of the BH1750 to the ambient light
+const LIGHT_AUTO_RANGE : bool = true;
+
+/// Display contrast and precharge from the given 1/100 lux on, dim in the
+/// dark and bright in daylight
+static DIM_CURVE : [screen::Brightness; 4] = [
+    screen::Brightness { lux: 0, contrast: 0x01, precharge: 0x11 },
+    screen::Brightness { lux: 1_000, contrast: 0x30, precharge: 0x22 },
+    screen::Brightness { lux: 10_000, contrast: 0x8F, precharge: 0xF1 },
+    screen::Brightness { lux: 100_000, contrast: 0xFF, precharge: 0xF1 },
+];
+/// Percent the light has to move past a threshold of `DIM_CURVE`
+const DIM_HYSTERESIS : u32 = 20;
+
+/// Missed reads or conversions after which a reading is stale
+const STALE_INTERVALS : u64 = 3;
+/// Ticks without a reading after which a continuously converting RTD is stale
+const RTD_DEADLINE : u64 = 200;
+
+fn rtd_deadline() -> u64 {
+    match SAMPLING {
+        Sampling::Continuous => RTD_DEADLINE,
+        Sampling::OneShot(interval) => interval * STALE_INTERVALS,
+    }
 }
 
-static mut READ_STATE : ReadState = ReadState::Conf;
-static mut LAST_TEMP : u16 = 0;
+/// Pin on port B of the 1-Wire bus with DS18B20 sensors
+const ONEWIRE_PIN : Option<u8> = None;
+const DS18B20_RESOLUTION : Resolution = Resolution::Bits12;
+/// Ticks between conversions of the DS18B20s, at least the conversion time
+const DS18B20_INTERVAL : u64 = 1000;
+
+/// Calibration of each probe in 1/100 °C, as printed at the end of a
+/// calibration run
+const CALIBRATION : [Calibration; MAX_PROBES] = [Calibration::None; MAX_PROBES];
+/// Probe to calibrate in ice water and boiling water after start up
+const CALIBRATE_PROBE : Option<u8> = None;
+/// Boiling point of water at the local air pressure in 1/100 of `UNIT`
+const BOILING_POINT : i32 = 10000;
+
+/// Filter applied to the temperatures before they are displayed
+const FILTER : filter::Kind = filter::Kind::Median;
+const FILTER_WINDOW : usize = 5;
+/// Samples further than this from the filtered value in 1/100 °C are
+/// dropped as spikes
+const SPIKE_DELTA : Option<i32> = Some(200);
+
+/// Ticks over which the rate of change is computed
+const RATE_WINDOW : u64 = 30_000;
+const RATE_SAMPLES : usize = 30;
+
+static mut BH1750 : Option<Bh1750> = None;
+static mut DIMMER : Option<screen::Dimmer> = None;
+/// Set while any reading is stale, heater control has to switch off
+static mut FAIL_SAFE : bool = false;
+static mut MAX31865 : [Option<Max31865<Unconfigured>>; MAX_PROBES] = [None, None, None];
+static mut THERMOCOUPLE_CS : [Option<ChipSelect>; MAX_PROBES] = [None; MAX_PROBES];
+static mut PROBES : Option<Probes> = None;
+static mut ONEWIRE : Option<OneWire> = None;
+static mut DS18B20 : Option<Ds18b20s> = None;
+static mut LAST_TEMP : [Option<i32>; MAX_PROBES] = [None; MAX_PROBES];
+static mut FILTER_WINDOWS : [[i32; FILTER_WINDOW]; MAX_PROBES] = [[0; FILTER_WINDOW]; MAX_PROBES];
+static mut FILTERS : [Option<filter::Filter<'static>>; MAX_PROBES] = [None, None, None];
+static mut RATE_BUFFERS : [[(u64, i32); RATE_SAMPLES]; MAX_PROBES] = [[(0, 0); RATE_SAMPLES]; MAX_PROBES];
+static mut RATES : [Option<Rate<'static>>; MAX_PROBES] = [None, None, None];
 static mut LAST_READ : u64 = 0;
+static mut CALIBRATING : Option<(u8, Procedure)> = None;
+
+/// Set up calibration, filtering, rate of change and the freshness deadline
+/// of a channel.
+fn setup_probe<T>(sensor: &mut T, channel: u8, deadline: u64)
+where
+    T : TemperatureSensor
+{
+    let idx = channel as usize;
+    unsafe { WATCHDOG.watch(channel, deadline, CNTR); }
+    sensor.set_calibration(channel, CALIBRATION[idx]);
+    unsafe {
+        let window = &mut FILTER_WINDOWS[idx];
+        FILTERS[idx] = Some(filter::Filter::new(FILTER, window, SPIKE_DELTA));
+        let buffer = &mut RATE_BUFFERS[idx];
+        RATES[idx] = Some(Rate::new(buffer, RATE_WINDOW));
+    }
+    if CALIBRATE_PROBE == Some(channel) {
+        iprintln!("calibrating {}: put the probe into ice water", channel);
+        unsafe { CALIBRATING = Some((channel, Procedure::two_point(0, UNIT.to_celsius(BOILING_POINT)))); }
+    }
+}
+
+fn sensor_stale(channel: u8) {
+    iprintln!("stale {}: no reading in time", channel);
+    unsafe { FAIL_SAFE = true; }
+}
+
+fn temperature_alarm(alarm: Alarm) {
+    match alarm {
+        Alarm::High => { iprintln!("alarm: temperature too high"); }
+        Alarm::Low => { iprintln!("alarm: temperature too low"); }
+    }
+}
 
-fn spi_interrupt(t: &mut Threshold, r: SPI2::Resources) {
+/// Feed a reading to a running calibration of `channel`, the calibration is
+/// applied once all reference points have been captured.
+fn calibrate<T>(sensor: &mut T, channel: u8, uncalibrated: Temperature)
+where
+    T : TemperatureSensor
+{
+    let calibrating = unsafe { &mut CALIBRATING };
+    let calibration = match *calibrating {
+        Some((id, ref mut procedure)) if id == channel => {
+            let step = procedure.step();
+            let calibration = procedure.sample(uncalibrated.centi_celsius());
+            if step == calibration::Step::Low && procedure.step() == calibration::Step::High {
+                iprintln!("calibrating {}: put the probe into boiling water", channel);
+            }
+            calibration
+        }
+        _ => return
+    };
+
+    if let Some(calibration) = calibration {
+        match calibration {
+            Calibration::TwoPoint { gain, offset } => {
+                iprintln!("calibrated {}: gain {} offset {}", channel, gain, offset);
+            }
+            Calibration::Offset(offset) => { iprintln!("calibrated {}: offset {}", channel, offset); }
+            Calibration::None => {}
+        }
+        sensor.set_calibration(channel, calibration);
+        *calibrating = None;
+    }
+}
+
+/// Display page showing the temperature of a channel, page 1 is taken by the
+/// activity indicator.
+fn probe_page(channel: u8) -> Option<u8> {
+    let page = if channel == 0 { 0 } else { channel + 1 };
+    if page < PANEL.pages() { Some(page) } else { None }
+}
+
+/// Filter, display and log all readings the sensor has available.
+fn show_readings<T>(sensor: &mut T)
+where
+    T : TemperatureSensor
+{
+    while let Some(reading) = sensor.take() {
+        // there is no room on the display for more channels
+        if reading.channel as usize >= MAX_PROBES {
+            continue;
+        }
+
+        unsafe {
+            if WATCHDOG.feed(reading.channel, reading.time) {
+                iprintln!("stale {}: recovered", reading.channel);
+                FAIL_SAFE = WATCHDOG.any_stale();
+            }
+        }
+
+        match reading.value {
+            Ok(measurement) => {
+                calibrate(sensor, reading.channel, measurement.uncalibrated);
+                show_temperature(reading.channel, reading.time, measurement.temperature);
+            }
+            Err(e) => show_error(reading.channel, e),
+        }
+    }
+}
+
+fn show_temperature(channel: u8, time: u64, temp: Temperature) {
+    let temp = temp.centi_celsius();
+    let idx = channel as usize;
+
+    let temp = match unsafe { FILTERS[idx].as_mut() } {
+        Some(filter) => match filter.add(temp) {
+            Some(temp) => temp,
+            None => {
+                iprintln!("temp {}: spike {}", channel, temp);
+                return;
+            }
+        },
+        None => temp
+    };
+    iprintln!("temp {}: {}", channel, temp);
+
+    if let Some(rate) = unsafe { RATES[idx].as_mut() } {
+        rate.add(time, temp);
+    }
+
+    // only redraw if the displayed value changes
+    let temp = UNIT.from_celsius(temp);
+    let last = unsafe { &mut LAST_TEMP[idx] };
+    if *last != Some(temp) {
+        *last = Some(temp);
+        if let Some(page) = probe_page(channel) {
+            let mut line = screen::Line::new();
+            screen::write_temperature(&mut line, temp, UNIT);
+            screen::show(0, page, &line);
+        }
+    }
+}
+
+/// Drop the history of a channel which has no valid temperature.
+fn forget_temperature(channel: u8) {
+    let idx = channel as usize;
+    unsafe {
+        if let Some(ref mut filter) = FILTERS[idx] {
+            filter.reset();
+        }
+        if let Some(ref mut rate) = RATES[idx] {
+            rate.reset();
+        }
+        // force the temperature to be redrawn once it is valid again
+        LAST_TEMP[idx] = None;
+    }
+}
+
+/// Replace the temperature of a channel which stopped producing readings.
+fn show_stale(channel: u8) {
+    forget_temperature(channel);
+    if let Some(page) = probe_page(channel) {
+        let mut line = screen::Line::new();
+        screen::write_stale(&mut line);
+        screen::show(0, page, &line);
+    }
+}
+
+fn show_error(channel: u8, error: SensorError) {
+    forget_temperature(channel);
+    match error {
+        SensorError::Fault(code) => { iprintln!("fault {}: {}", channel, code); }
+        _ => { iprintln!("temp {}: out of range", channel); }
+    }
+
+    if let Some(page) = probe_page(channel) {
+        let mut line = screen::Line::new();
+        match error {
+            SensorError::Fault(code) => screen::write_fault(&mut line, code),
+            _ => screen::write_error(&mut line),
+        }
+        screen::show(0, page, &line);
+    }
+}
+
+fn spi_interrupt(_t: &mut Threshold, r: SPI2::Resources) {
     let spi_res = unsafe { &mut SPI_RES };
     let spi = Spi(&*r.SPI2_REG);
 
     spi_res.process_int(&spi);
 
-    unsafe {
-        match SPI_RES.state {
-            SpiState::Finished =>
-            {
-                match READ_STATE {
-                    ReadState::Conf => {
-                        SPI_RES.start_read(0x02, &spi);
-                        READ_STATE = ReadState::Lsb;
-                    }
-                    ReadState::Lsb => {
-                        SPI_RES.start_read(0x01, &spi);
-                        READ_STATE = ReadState::Msb(SPI_RES.result);
-                    }
-                    ReadState::Msb(lsb) => {
-                        let val : u16 = ((SPI_RES.result as u16) << 8) | (lsb as u16);
-                        if val != LAST_TEMP {
-                            LAST_TEMP = val;
-                            iprint!("val: {} ", val);
-                            let conv = ((val >> 1) as u32 * 43234) >> 15;
-                            let temp = temp_conversion::lookup_temperature(conv as u16);
-
-                            screen::set_address(t, &r.I2C1, 0, 0);
-                            // ensure the number is completely covered by making sure 
-                            // we always print 5 digits
-                            if temp < 10000 {
-                                screen::write_empty_digit(t, &r.I2C1);
-                            }
-                            screen::write_number(t, &r.I2C1, temp / 100);
-                            screen::write_dot(t, &r.I2C1);
-                            screen::write_number(t, &r.I2C1, temp % 100);
-                            iprintln!("-> {}", temp);
-                        }
-
-                        // read next value
-                        READ_STATE = ReadState::Lsb;
-                        // SPI_RES.start_read(0x02, &spi);
-                    }
-                }
-                // SPI_RES.state = SpiState::Idle;  
-            }
-            _ => {}
-        }
+    let probes = match unsafe { &mut PROBES } {
+        &mut Some(ref mut probes) => probes,
+        &mut None => return
+    };
+
+    if let SpiState::Finished = spi_res.state {
+        probes.on_spi(unsafe { CNTR }, spi_res, &spi);
+        show_readings(probes);
     }
 }
 
@@ -258,33 +611,83 @@ fn timer2_interrupt(t: &mut Threshold, r: TIM2::Resources) {
 
     unsafe {
         CNTR += 1;
+
+        if let Some(ref mut probes) = PROBES {
+            let spi = Spi(&*r.SPI2_REG);
+            probes.poll(CNTR, &mut SPI_RES, &spi);
+        }
+
+        if let Some(ref mut ds18b20) = DS18B20 {
+            ds18b20.tick(CNTR);
+            show_readings(ds18b20);
+        }
+
+        while let Some(channel) = WATCHDOG.check(CNTR) {
+            show_stale(channel);
+        }
+
+        if let Some(ref mut bh1750) = BH1750 {
+            match bh1750.poll(CNTR, t, &r.I2C1) {
+                Some(Ok(lux)) => {
+                    if let Some(ref mut dimmer) = DIMMER {
+                        dimmer.update(t, &r.I2C1, lux);
+                    }
+                }
+                Some(Err(_)) => { iprintln!("BH1750 read failed"); }
+                None => {}
+            }
+        }
     }
 
     if unsafe { CNTR } % 1000 == 0 {
         iprintln!("ext {}", tim2.sr.read().bits());
-        screen::set_address(t, &r.I2C1, 0, 1);
-    }
-    if unsafe { CNTR } % 2000 == 0 {
-        screen::write_dot(t, &r.I2C1);
-    } else if unsafe { CNTR } % 2000 == 1000 {
-        screen::write_empty_digit(t, &r.I2C1);
+        // activity dot left of the rate
+        unsafe { FRAMEBUFFER.set_pixel(1, 15, CNTR % 2000 == 0); }
+
+        show_rate();
     }
 
+    unsafe { FRAMEBUFFER.flush(t, &r.I2C1); }
+}
+
+/// Show the rate of change of the first probe per minute next to the
+/// activity indicator.
+fn show_rate() {
+    let rate = match unsafe { &RATES[0] } {
+        &Some(ref rate) => rate.per_minute(),
+        &None => None
+    };
+
+    if let Some(rate) = rate {
+        let mut line = screen::Line::new();
+        screen::write_temperature(&mut line, UNIT.delta_from_celsius(rate), UNIT);
+        screen::show(8, 1, &line);
+    }
 }
 
 fn external_interrupt(_t: &mut Threshold, r: EXTI9_5::Resources) {
     unsafe {
         let c = CNTR;
         let took = c - LAST_READ;
-        iprintln!("hz {} {}", 1000 / took, took);
+        // several probes can signal DRDY within the same tick
+        if took > 0 {
+            iprintln!("hz {} {}", 1000 / took, took);
+        }
         LAST_READ = c;
     }
 
     let spi = Spi(&*r.SPI2_REG);
 
-    if r.EXTI.pr.read().pr8().bit_is_set() {
-        unsafe { SPI_RES.start_read(0x02, &spi); }
-
-        r.EXTI.pr.modify(|_, w| w.pr8().set_bit());
+    unsafe {
+        if let Some(ref mut probes) = PROBES {
+            probes.on_drdy(&r.EXTI);
+            // start reading unless the bus is still busy with another probe
+            probes.poll(CNTR, &mut SPI_RES, &spi);
+        } else {
+            // not sampling yet, just acknowledge the DRDY lines
+            for &(_, drdy, _) in PROBE_PINS.iter() {
+                drdy.clear(&r.EXTI);
+            }
+        }
     }
-}
\ No newline at end of file
+}
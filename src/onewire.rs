@@ -0,0 +1,240 @@
+#[macro_use]
+#[allow(unused_imports)]
+use debug;
+use stm32;
+use cortex_m;
+
+use cortex_m::peripheral::{Peripherals, DWT};
+
+/// Core clock cycles per microsecond, the core runs from the 8 MHz HSI.
+const CYCLES_PER_US : u32 = 8;
+
+pub const CMD_SEARCH_ROM : u8 = 0xF0;
+pub const CMD_MATCH_ROM : u8 = 0x55;
+pub const CMD_SKIP_ROM : u8 = 0xCC;
+
+/// Busy wait using the DWT cycle counter enabled by `OneWire::new`.
+fn delay_us(us: u32) {
+    let dwt = unsafe { &*DWT::ptr() };
+    let start = dwt.cyccnt.read();
+    let cycles = us * CYCLES_PER_US;
+    while dwt.cyccnt.read().wrapping_sub(start) < cycles { }
+}
+
+/// CRC8 with the polynomial x^8 + x^5 + x^4 + 1 used for ROM codes and
+/// scratchpads. The CRC over data including its CRC byte is 0.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        let mut b = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ b) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            b >>= 1;
+        }
+    }
+    crc
+}
+
+/// 64 bit ROM code of a device, family code first.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Rom(pub [u8; 8]);
+
+impl Rom {
+    pub fn family(&self) -> u8 {
+        self.0[0]
+    }
+
+    pub fn crc_ok(&self) -> bool {
+        crc8(&self.0) == 0
+    }
+}
+
+/// Bit-banged 1-Wire master on an open drain pin with external pull-up.
+///
+/// Every time slot is run with interrupts disabled, a reset takes about 1 ms
+/// and a byte about 0.6 ms so the bus can be driven one step per TIM2 tick.
+#[derive(Clone, Copy)]
+pub struct OneWire {
+    port : *const stm32::gpioa::RegisterBlock,
+    pin : u8,
+}
+
+impl OneWire {
+    /// Configure `pin` of `port` as a 10 MHz open drain output and enable the
+    /// cycle counter used for timing.
+    pub fn new(port: &stm32::gpioa::RegisterBlock, pin: u8) -> OneWire {
+        let bus = OneWire {
+            port: port as *const _,
+            pin: pin,
+        };
+
+        bus.release();
+
+        // general purpose open drain output, 10 MHz
+        let shift = (pin % 8) * 4;
+        if pin < 8 {
+            port.crl.modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << shift) | (0b0101 << shift)) });
+        } else {
+            port.crh.modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << shift) | (0b0101 << shift)) });
+        }
+
+        let mut p = unsafe { Peripherals::steal() };
+        p.DCB.enable_trace();
+        p.DWT.enable_cycle_counter();
+
+        bus
+    }
+
+    fn port(&self) -> &stm32::gpioa::RegisterBlock {
+        unsafe { &*self.port }
+    }
+
+    fn low(&self) {
+        self.port().bsrr.write(|w| unsafe { w.bits(1 << (self.pin + 16)) });
+    }
+
+    fn release(&self) {
+        self.port().bsrr.write(|w| unsafe { w.bits(1 << self.pin) });
+    }
+
+    fn is_high(&self) -> bool {
+        self.port().idr.read().bits() & (1 << self.pin) != 0
+    }
+
+    /// Send a reset pulse, returns whether a device answered with a presence
+    /// pulse.
+    pub fn reset(&self) -> bool {
+        let present = cortex_m::interrupt::free(|_| {
+            self.low();
+            delay_us(480);
+            self.release();
+            delay_us(70);
+            !self.is_high()
+        });
+        delay_us(410);
+        present
+    }
+
+    pub fn write_bit(&self, bit: bool) {
+        cortex_m::interrupt::free(|_| {
+            self.low();
+            if bit {
+                delay_us(6);
+                self.release();
+                delay_us(64);
+            } else {
+                delay_us(60);
+                self.release();
+                delay_us(10);
+            }
+        });
+    }
+
+    pub fn read_bit(&self) -> bool {
+        cortex_m::interrupt::free(|_| {
+            self.low();
+            delay_us(6);
+            self.release();
+            delay_us(9);
+            let bit = self.is_high();
+            delay_us(55);
+            bit
+        })
+    }
+
+    /// Write a byte, least significant bit first.
+    pub fn write_byte(&self, byte: u8) {
+        for i in 0..8 {
+            self.write_bit(byte & (1 << i) != 0);
+        }
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit() {
+                byte |= 1 << i;
+            }
+        }
+        byte
+    }
+
+    /// Address the device `rom` with the following command, after a reset.
+    pub fn select(&self, rom: &Rom) {
+        self.write_byte(CMD_MATCH_ROM);
+        for b in rom.0.iter() {
+            self.write_byte(*b);
+        }
+    }
+
+    /// Find the devices on the bus with a matching `family` code, or any if
+    /// it is `None`. Returns the number of ROM codes stored in `roms`.
+    pub fn search(&self, family: Option<u8>, roms: &mut [Rom]) -> usize {
+        let mut found = 0;
+        let mut rom = [0u8; 8];
+        // bit position of the last path taken with a 0 at a discrepancy
+        let mut last_discrepancy = 0;
+
+        while found < roms.len() {
+            if !self.reset() {
+                break;
+            }
+            self.write_byte(CMD_SEARCH_ROM);
+
+            let mut discrepancy = 0;
+            for bit in 1..65 {
+                let byte = (bit - 1) / 8;
+                let mask = 1 << ((bit - 1) % 8);
+
+                let id = self.read_bit();
+                let complement = self.read_bit();
+
+                let direction = match (id, complement) {
+                    // no device left on the bus
+                    (true, true) => return found,
+                    (true, false) => true,
+                    (false, true) => false,
+                    (false, false) => {
+                        // devices differ, take 1 at the last discrepancy and
+                        // repeat the earlier choices before it
+                        let direction = if bit == last_discrepancy {
+                            true
+                        } else if bit > last_discrepancy {
+                            false
+                        } else {
+                            rom[byte] & mask != 0
+                        };
+                        if !direction {
+                            discrepancy = bit;
+                        }
+                        direction
+                    }
+                };
+
+                if direction {
+                    rom[byte] |= mask;
+                } else {
+                    rom[byte] &= !mask;
+                }
+                self.write_bit(direction);
+            }
+
+            let code = Rom(rom);
+            if code.crc_ok() && family.map_or(true, |f| code.family() == f) {
+                roms[found] = code;
+                found += 1;
+            }
+
+            last_discrepancy = discrepancy;
+            if last_discrepancy == 0 {
+                break;
+            }
+        }
+
+        found
+    }
+}
\ No newline at end of file
@@ -0,0 +1,116 @@
+use cyclicbuffer::CyclicBuffer;
+
+/// TIM2 ticks per minute.
+pub const TICKS_PER_MINUTE : u64 = 60_000;
+
+/// Rate of change of a temperature, the slope of a least squares fit through
+/// the samples of the last `window` ticks.
+///
+/// Samples are kept at most once every `window / len` ticks so the buffer
+/// covers the whole window regardless of the conversion rate.
+pub struct Rate<'a> {
+    samples : CyclicBuffer<'a, (u64, i32)>,
+    window : u64,
+}
+
+impl<'a> Rate<'a> {
+    pub fn new(buffer: &'a mut [(u64, i32)], window: u64) -> Rate<'a> {
+        Rate {
+            samples: CyclicBuffer { data: buffer, ptr: 0, len: 0 },
+            window: window,
+        }
+    }
+
+    fn interval(&self) -> u64 {
+        self.window / self.samples.data.len() as u64
+    }
+
+    /// Add a temperature in 1/100 degrees taken at tick `now`.
+    pub fn add(&mut self, now: u64, temp: i32) {
+        if let Some((last, _)) = self.last() {
+            if now < last + self.interval() {
+                return;
+            }
+        }
+
+        // drop samples which fell out of the window
+        while let Some((time, _)) = self.samples.peak() {
+            if time + self.window >= now && !self.samples.full() {
+                break;
+            }
+            self.samples.read();
+        }
+
+        self.samples.write((now, temp));
+    }
+
+    /// Drop all samples, e.g. after the sensor failed.
+    pub fn reset(&mut self) {
+        while let Some(_) = self.samples.read() {}
+    }
+
+    /// The newest sample.
+    pub fn last(&self) -> Option<(u64, i32)> {
+        if self.samples.empty() {
+            None
+        } else {
+            self.samples.get(self.samples.length() - 1)
+        }
+    }
+
+    /// Slope in 1/100 degrees per minute, `None` until there are at least two
+    /// samples.
+    pub fn per_minute(&self) -> Option<i32> {
+        let (t0, x0) = match self.samples.peak() {
+            Some(first) => first,
+            None => return None
+        };
+
+        // relative to the first sample to keep the sums small
+        let n = self.samples.length() as i64;
+        let (mut st, mut sx, mut stt, mut stx) = (0i64, 0i64, 0i64, 0i64);
+        for i in 0..self.samples.length() {
+            if let Some((time, temp)) = self.samples.get(i) {
+                let dt = (time - t0) as i64;
+                let dx = (temp - x0) as i64;
+                st += dt;
+                sx += dx;
+                stt += dt * dt;
+                stx += dt * dx;
+            }
+        }
+
+        let den = n * stt - st * st;
+        if den == 0 {
+            return None;
+        }
+        Some(((n * stx - st * sx) * TICKS_PER_MINUTE as i64 / den) as i32)
+    }
+
+    /// Whether the temperature changes faster than `max` 1/100 degrees per
+    /// minute in either direction.
+    pub fn exceeds(&self, max: i32) -> bool {
+        match self.per_minute() {
+            Some(rate) => rate > max || rate < -max,
+            None => false
+        }
+    }
+
+    /// Ticks until `target` is reached at the current rate, `None` if the
+    /// temperature does not move towards it.
+    pub fn time_to(&self, target: i32) -> Option<u64> {
+        let (rate, (_, temp)) = match (self.per_minute(), self.last()) {
+            (Some(rate), Some(last)) => (rate, last),
+            _ => return None
+        };
+
+        let remaining = (target - temp) as i64;
+        if remaining == 0 {
+            return Some(0);
+        }
+        if rate == 0 || (remaining < 0) != (rate < 0) {
+            return None;
+        }
+        Some((remaining * TICKS_PER_MINUTE as i64 / rate as i64) as u64)
+    }
+}
\ No newline at end of file
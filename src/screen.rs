@@ -4,6 +4,8 @@
 use debug;
 use stm32;
 use ssd1306;
+use framebuffer::FRAMEBUFFER;
+use temp_conversion::Unit;
 
 use i2c::I2c;
 use rtfm::{Resource, Threshold};
@@ -14,7 +16,8 @@ pub fn init_screen<'a>(
     i2c1: &'a stm32::I2C1,
     pinb8: GpioPinDefault<'a, stm32::GPIOB, Pin8>, 
     pinb9: GpioPinDefault<'a, stm32::GPIOB, Pin9>,
-    afio_i2c1: AfioI2C1Peripheral<'a, NotConfigured>) {
+    afio_i2c1: AfioI2C1Peripheral<'a, NotConfigured>,
+    geometry: ssd1306::Geometry) {
     
     let pinb8 = pinb8.set_output_10MHz().set_alt_output_open_drain();
     let pinb9 = pinb9.set_output_10MHz().set_alt_output_open_drain();
@@ -29,7 +32,7 @@ pub fn init_screen<'a>(
     let ports = r.3.set_ports_remapped(pinb8, pinb9, afio_i2c1);
     i2c1.complete_init(bsm, freq, trise, ports);
 
-    ssd1306::sync_init(&i2c1);
+    ssd1306::sync_init(&i2c1, geometry);
 }  
 
 pub fn set_address_mode<'a, S>(
@@ -40,59 +43,255 @@ where
     ssd1306::write_control_2(t, i2c1, &[0x20, 0]);   
 }
 
+/// Set the position of the following writes, which wrap at the right edge
+/// of the panel. Returns false if the position is off the panel.
 pub fn set_address<'a, S>(
     t: &mut Threshold,
     i2c1: &'a S,
     column: u8,
-    page: u8) 
+    page: u8) -> bool
 where
     S : Resource<Data = stm32::I2C1> {
-    ssd1306::write_control_2(t, i2c1, &[0x21, column, 127, 0x22, page, 7]);   
+    let geometry = ssd1306::geometry();
+    if column >= geometry.width() || page >= geometry.pages() {
+        return false;
+    }
+
+    let offset = geometry.column_offset();
+    ssd1306::write_control_2(t, i2c1, &[
+        0x21, offset + column, offset + geometry.width() - 1,
+        0x22, page, geometry.pages() - 1])
 }
 
-pub fn write_digit<'a, S>(
-    t: &mut Threshold,
-    i2c1: &'a S,
-    num: u8)
-where
-    S : Resource<Data = stm32::I2C1> 
-{
-    let num = &ssd1306::NUMBERS[num as usize];
-    ssd1306::write_data(t, i2c1, num);
-    ssd1306::write_data(t, i2c1, &[0, 0]);
+/// Most columns of a `Line`, the width of the widest panel.
+const MAX_LINE : usize = 128;
+
+/// Columns of a field composed by the `write_*` functions, which `show`
+/// draws into the framebuffer.
+pub struct Line {
+    columns : [u8; MAX_LINE],
+    len : usize,
 }
 
-pub fn write_dot<'a, S>(
-    t: &mut Threshold,
-    i2c1: &'a S)
-where
-    S : Resource<Data = stm32::I2C1> 
-{
-    ssd1306::write_data(t, i2c1, &[0, 1, 0]);
+impl Line {
+    pub fn new() -> Line {
+        Line {
+            columns: [0; MAX_LINE],
+            len: 0,
+        }
+    }
+
+    /// Append columns, whatever doesn't fit is dropped.
+    pub fn push(&mut self, columns: &[u8]) {
+        for c in columns.iter() {
+            if self.len >= MAX_LINE {
+                return;
+            }
+            self.columns[self.len] = *c;
+            self.len += 1;
+        }
+    }
+
+    pub fn columns(&self) -> &[u8] {
+        &self.columns[..self.len]
+    }
 }
 
-pub fn write_empty_digit<'a, S>(
-    t: &mut Threshold,
-    i2c1: &'a S)
-where
-    S : Resource<Data = stm32::I2C1> 
-{
-    ssd1306::write_data(t, i2c1, &[0; 7]);
+/// Draw `line` at `page` from `column` on, clipped at the right edge of the
+/// panel. It is sent with the next flush of the framebuffer.
+pub fn show(column: u8, page: u8, line: &Line) {
+    unsafe { FRAMEBUFFER.write_columns(page as usize, column as usize, line.columns()); }
 }
 
-pub fn write_number<'a, S>(
-    t: &mut Threshold,
-    i2c1: &'a S,
-    num: u32)
-where
-    S : Resource<Data = stm32::I2C1>
-{
+pub fn write_digit(line: &mut Line, num: u8) {
+    line.push(&ssd1306::NUMBERS[num as usize]);
+    line.push(&[0, 0]);
+}
+
+pub fn write_dot(line: &mut Line) {
+    line.push(&[0, 1, 0]);
+}
+
+pub fn write_dash(line: &mut Line) {
+    line.push(&[0b00010000; 5]);
+    line.push(&[0, 0]);
+}
+
+/// Show a fault code in place of a temperature as `--n`, clearing the rest
+/// of the space taken by a number.
+pub fn write_fault(line: &mut Line, code: u8) {
+    write_dash(line);
+    write_dash(line);
+    write_digit(line, code);
+    line.push(&[0; 17 + UNIT_WIDTH]);
+}
+
+/// Show dots in place of a temperature which has not been updated in time.
+pub fn write_stale(line: &mut Line) {
+    for _ in 0..5 {
+        line.push(&[0, 0, 1, 0, 0, 0, 0]);
+    }
+    line.push(&[0; 3 + UNIT_WIDTH]);
+}
+
+/// Show dashes in place of a temperature which could not be converted.
+pub fn write_error(line: &mut Line) {
+    for _ in 0..5 {
+        write_dash(line);
+    }
+    line.push(&[0; 3 + UNIT_WIDTH]);
+}
+
+/// Columns taken by `write_unit`.
+pub const UNIT_WIDTH : usize = 11;
+
+/// Show the unit as °C, °F or K.
+pub fn write_unit(line: &mut Line, unit: Unit) {
+    let letter = match unit {
+        Unit::Celsius => 0,
+        Unit::Fahrenheit => 1,
+        Unit::Kelvin => {
+            line.push(&[0; 4]);
+            line.push(&ssd1306::UNITS[2]);
+            line.push(&[0, 0]);
+            return;
+        }
+    };
+    line.push(&ssd1306::DEGREE);
+    line.push(&[0]);
+    line.push(&ssd1306::UNITS[letter]);
+    line.push(&[0, 0]);
+}
+
+/// Show a temperature in 1/100 of `unit` followed by the unit.
+pub fn write_temperature(line: &mut Line, temp: i32, unit: Unit) {
+    // ensure the number is completely covered by making sure 
+    // we always print 5 digits
+    if temp < 0 {
+        write_dash(line);
+    } else if temp < 10000 {
+        write_empty_digit(line);
+    }
+    let abs = (if temp < 0 { -temp } else { temp }) as u32;
+    write_number(line, abs / 100);
+    write_dot(line);
+    write_digit(line, (abs % 100 / 10) as u8);
+    write_digit(line, (abs % 10) as u8);
+    write_unit(line, unit);
+    // covers the unit of a longer number shown before
+    write_empty_digit(line);
+}
+
+/// Show `text` in `ssd1306::FONT` with `spacing` blank columns after each
+/// character, whatever is past the right edge of the panel is dropped.
+pub fn write_text(line: &mut Line, text: &str, spacing: u8) {
+    for c in text.chars() {
+        line.push(ssd1306::glyph(c));
+        for _ in 0..spacing {
+            line.push(&[0]);
+        }
+    }
+}
+
+pub fn write_empty_digit(line: &mut Line) {
+    line.push(&[0; 7]);
+}
+
+pub fn write_number(line: &mut Line, num: u32) {
     let digit = num % 10;
     let rem = num / 10;
 
     if rem > 0 {
-        write_number(t, i2c1, rem);
+        write_number(line, rem);
     }
 
-    write_digit(t, i2c1, digit as u8);
-} 
\ No newline at end of file
+    write_digit(line, digit as u8);
+} 
+
+/// A point of the brightness curve, used from `lux` (in 1/100 lux) on.
+#[derive(Clone, Copy)]
+pub struct Brightness {
+    pub lux : u32,
+    pub contrast : u8,
+    pub precharge : u8,
+}
+
+/// Adjusts the display brightness to the ambient light along a curve of
+/// points sorted by ascending lux.
+///
+/// A brighter point is only picked once the light is `hysteresis` percent
+/// above its threshold, and a darker one once it is `hysteresis` percent
+/// below the threshold of the current point, so the brightness doesn't pump
+/// around a threshold. A hysteresis above 100 percent is limited to 100.
+pub struct Dimmer {
+    curve : &'static [Brightness],
+    hysteresis : u32,
+    level : Option<usize>,
+}
+
+impl Dimmer {
+    pub fn new(curve: &'static [Brightness], hysteresis: u32) -> Dimmer {
+        Dimmer {
+            curve: curve,
+            // 100 - hysteresis would underflow
+            hysteresis: hysteresis.min(100),
+            level: None,
+        }
+    }
+
+    /// Index of the curve point for `lux` without hysteresis.
+    fn point(&self, lux: u32) -> usize {
+        let mut level = 0;
+        for (i, point) in self.curve.iter().enumerate() {
+            if lux >= point.lux {
+                level = i;
+            }
+        }
+        level
+    }
+
+    /// Feed an ambient light reading, changes the contrast and precharge of
+    /// the display if the brightness level changed.
+    pub fn update<'a, S>(
+        &mut self,
+        t: &mut Threshold,
+        i2c1: &'a S,
+        lux: u32)
+    where
+        S : Resource<Data = stm32::I2C1>
+    {
+        if self.curve.is_empty() {
+            return;
+        }
+
+        let target = self.point(lux);
+        let level = match self.level {
+            None => target,
+            Some(level) if target > level => {
+                // the light may have jumped past several points, take the
+                // brightest one whose threshold it clears
+                let mut next = level;
+                for i in level + 1..target + 1 {
+                    let threshold = self.curve[i].lux as u64 * (100 + self.hysteresis) as u64 / 100;
+                    if lux as u64 >= threshold {
+                        next = i;
+                    }
+                }
+                next
+            }
+            Some(level) if target < level => {
+                let threshold = self.curve[level].lux as u64 * (100 - self.hysteresis) as u64 / 100;
+                if (lux as u64) < threshold { target } else { level }
+            }
+            Some(level) => level
+        };
+
+        if self.level != Some(level) {
+            let point = self.curve[level];
+            // with the I2C queue full the next reading tries again
+            if ssd1306::set_contrast(t, i2c1, point.contrast, point.precharge) {
+                self.level = Some(level);
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,73 @@
+use calibration::Calibration;
+use temp_conversion::Unit;
+
+/// Temperature in 1/100 °C.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub struct Temperature(pub i32);
+
+impl Temperature {
+    pub fn from_unit(unit: Unit, temp: i32) -> Temperature {
+        Temperature(unit.to_celsius(temp))
+    }
+
+    pub fn centi_celsius(&self) -> i32 {
+        self.0
+    }
+
+    /// The temperature in 1/100 of `unit`.
+    pub fn in_unit(&self, unit: Unit) -> i32 {
+        unit.from_celsius(self.0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SensorError {
+    /// The sensor value is outside of the supported range
+    OutOfRange,
+    /// The sensor value cannot come from a working sensor
+    Implausible,
+    /// The sensor reported a fault, with the code shown on the display
+    Fault(u8),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Status {
+    /// No conversions have been started
+    Stopped,
+    Running,
+    /// The last reading failed
+    Failed(SensorError),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub temperature : Temperature,
+    /// Temperature before the calibration of the channel was applied
+    pub uncalibrated : Temperature,
+}
+
+/// Result of a conversion of one channel of a sensor.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub channel : u8,
+    /// TIM2 tick at which the reading was taken
+    pub time : u64,
+    pub value : Result<Measurement, SensorError>,
+}
+
+/// A source of temperature readings, possibly with several channels.
+///
+/// Drivers produce readings from their interrupt handlers, the application
+/// takes them with `take` without knowing about the sensor type.
+pub trait TemperatureSensor {
+    /// Number of channels, the channels are numbered from 0.
+    fn channels(&self) -> usize;
+
+    fn status(&self, channel: u8) -> Status;
+
+    /// Take the next reading which became available.
+    fn take(&mut self) -> Option<Reading>;
+
+    /// Correction applied to the temperatures of `channel`.
+    fn set_calibration(&mut self, channel: u8, calibration: Calibration);
+}
\ No newline at end of file
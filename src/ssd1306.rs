@@ -3,11 +3,10 @@
 use debug;
 use cortex_m;
 
-use core::any::Any;
-use tslib::i2c::{I2c, I2C, I2CState, I2cState, I2cStateOptions, Write};
+use tslib::i2c::{I2c, I2C, I2CState};
 use stm32::I2C1;
 
-use cyclicbuffer::CyclicBuffer;
+use i2cbus;
 
 const CMD_DISPLAYOFF : u8 = 0xAE;
 const CMD_SETDISPLAYCLOCKDIV : u8 = 0xD5;
@@ -27,6 +26,38 @@ const CMD_LOWERCOL : u8 = 0x00;
 const CMD_SETSTARTLINE : u8 = 0x40;
 const CMD_MEMORYMODE : u8 = 0x20;
 const CMD_INVERTDISPLAY : u8 = 0xA6;
+
+pub static DEGREE : [u8;3] = [
+    0b01000000,
+    0b10100000,
+    0b01000000,
+];
+
+/// Letters of the units C, F and K
+pub static UNITS : [[u8;5];3] = [
+    [ // C
+        0b01111110,
+        0b10000001,
+        0b10000001,
+        0b10000001,
+        0b01000010,
+    ],
+    [ // F
+        0b11111111,
+        0b10010000,
+        0b10010000,
+        0b10010000,
+        0b10000000,
+    ],
+    [ // K
+        0b11111111,
+        0b00011000,
+        0b00100100,
+        0b01000010,
+        0b10000001,
+    ],
+];
+
 pub static NUMBERS : [[u8;5];10] = [
     [ // 0
         0b01111110,
@@ -100,8 +131,174 @@ pub static NUMBERS : [[u8;5];10] = [
     ]
 ];
 
+/// First character of `FONT`.
+const FONT_FIRST : u8 = 0x20;
+
+/// 5x7 font of the printable ASCII characters from space to `~`, in the
+/// column format of `NUMBERS` with the bottom row left empty.
+pub static FONT : [[u8;5];95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0xFA, 0x00, 0x00], // !
+    [0x00, 0xE0, 0x00, 0xE0, 0x00], // "
+    [0x28, 0xFE, 0x28, 0xFE, 0x28], // #
+    [0x24, 0x54, 0xFE, 0x54, 0x48], // $
+    [0xC4, 0xC8, 0x10, 0x26, 0x46], // %
+    [0x6C, 0x92, 0xAA, 0x44, 0x0A], // &
+    [0x00, 0xA0, 0xC0, 0x00, 0x00], // '
+    [0x00, 0x38, 0x44, 0x82, 0x00], // (
+    [0x00, 0x82, 0x44, 0x38, 0x00], // )
+    [0x10, 0x54, 0x38, 0x54, 0x10], // *
+    [0x10, 0x10, 0x7C, 0x10, 0x10], // +
+    [0x00, 0x0A, 0x0C, 0x00, 0x00], // ,
+    [0x10, 0x10, 0x10, 0x10, 0x10], // -
+    [0x00, 0x06, 0x06, 0x00, 0x00], // .
+    [0x04, 0x08, 0x10, 0x20, 0x40], // /
+    [0x7C, 0x8A, 0x92, 0xA2, 0x7C], // 0
+    [0x00, 0x42, 0xFE, 0x02, 0x00], // 1
+    [0x42, 0x86, 0x8A, 0x92, 0x62], // 2
+    [0x84, 0x82, 0xA2, 0xD2, 0x8C], // 3
+    [0x18, 0x28, 0x48, 0xFE, 0x08], // 4
+    [0xE4, 0xA2, 0xA2, 0xA2, 0x9C], // 5
+    [0x3C, 0x52, 0x92, 0x92, 0x0C], // 6
+    [0x80, 0x8E, 0x90, 0xA0, 0xC0], // 7
+    [0x6C, 0x92, 0x92, 0x92, 0x6C], // 8
+    [0x60, 0x92, 0x92, 0x94, 0x78], // 9
+    [0x00, 0x6C, 0x6C, 0x00, 0x00], // :
+    [0x00, 0x6A, 0x6C, 0x00, 0x00], // ;
+    [0x10, 0x28, 0x44, 0x82, 0x00], // <
+    [0x28, 0x28, 0x28, 0x28, 0x28], // =
+    [0x00, 0x82, 0x44, 0x28, 0x10], // >
+    [0x40, 0x80, 0x8A, 0x90, 0x60], // ?
+    [0x4C, 0x92, 0x9E, 0x82, 0x7C], // @
+    [0x7E, 0x88, 0x88, 0x88, 0x7E], // A
+    [0xFE, 0x92, 0x92, 0x92, 0x6C], // B
+    [0x7C, 0x82, 0x82, 0x82, 0x44], // C
+    [0xFE, 0x82, 0x82, 0x44, 0x38], // D
+    [0xFE, 0x92, 0x92, 0x92, 0x82], // E
+    [0xFE, 0x90, 0x90, 0x80, 0x80], // F
+    [0x7C, 0x82, 0x82, 0x8A, 0x4C], // G
+    [0xFE, 0x10, 0x10, 0x10, 0xFE], // H
+    [0x00, 0x82, 0xFE, 0x82, 0x00], // I
+    [0x04, 0x02, 0x82, 0xFC, 0x80], // J
+    [0xFE, 0x10, 0x28, 0x44, 0x82], // K
+    [0xFE, 0x02, 0x02, 0x02, 0x02], // L
+    [0xFE, 0x40, 0x20, 0x40, 0xFE], // M
+    [0xFE, 0x20, 0x10, 0x08, 0xFE], // N
+    [0x7C, 0x82, 0x82, 0x82, 0x7C], // O
+    [0xFE, 0x90, 0x90, 0x90, 0x60], // P
+    [0x7C, 0x82, 0x8A, 0x84, 0x7A], // Q
+    [0xFE, 0x90, 0x98, 0x94, 0x62], // R
+    [0x62, 0x92, 0x92, 0x92, 0x8C], // S
+    [0x80, 0x80, 0xFE, 0x80, 0x80], // T
+    [0xFC, 0x02, 0x02, 0x02, 0xFC], // U
+    [0xF8, 0x04, 0x02, 0x04, 0xF8], // V
+    [0xFE, 0x04, 0x18, 0x04, 0xFE], // W
+    [0xC6, 0x28, 0x10, 0x28, 0xC6], // X
+    [0xC0, 0x20, 0x1E, 0x20, 0xC0], // Y
+    [0x86, 0x8A, 0x92, 0xA2, 0xC2], // Z
+    [0x00, 0xFE, 0x82, 0x82, 0x00], // [
+    [0x40, 0x20, 0x10, 0x08, 0x04], // \
+    [0x00, 0x82, 0x82, 0xFE, 0x00], // ]
+    [0x20, 0x40, 0x80, 0x40, 0x20], // ^
+    [0x02, 0x02, 0x02, 0x02, 0x02], // _
+    [0x00, 0x80, 0x40, 0x20, 0x00], // `
+    [0x04, 0x2A, 0x2A, 0x2A, 0x1E], // a
+    [0xFE, 0x12, 0x22, 0x22, 0x1C], // b
+    [0x1C, 0x22, 0x22, 0x22, 0x04], // c
+    [0x1C, 0x22, 0x22, 0x12, 0xFE], // d
+    [0x1C, 0x2A, 0x2A, 0x2A, 0x18], // e
+    [0x10, 0x7E, 0x90, 0x80, 0x40], // f
+    [0x10, 0x28, 0x2A, 0x2A, 0x3C], // g
+    [0xFE, 0x10, 0x20, 0x20, 0x1E], // h
+    [0x00, 0x22, 0xBE, 0x02, 0x00], // i
+    [0x04, 0x02, 0x22, 0xBC, 0x00], // j
+    [0x00, 0xFE, 0x08, 0x14, 0x22], // k
+    [0x00, 0x82, 0xFE, 0x02, 0x00], // l
+    [0x3E, 0x20, 0x18, 0x20, 0x1E], // m
+    [0x3E, 0x10, 0x20, 0x20, 0x1E], // n
+    [0x1C, 0x22, 0x22, 0x22, 0x1C], // o
+    [0x3E, 0x28, 0x28, 0x28, 0x10], // p
+    [0x10, 0x28, 0x28, 0x18, 0x3E], // q
+    [0x3E, 0x10, 0x20, 0x20, 0x10], // r
+    [0x12, 0x2A, 0x2A, 0x2A, 0x04], // s
+    [0x20, 0xFC, 0x22, 0x02, 0x04], // t
+    [0x3C, 0x02, 0x02, 0x04, 0x3E], // u
+    [0x38, 0x04, 0x02, 0x04, 0x38], // v
+    [0x3C, 0x02, 0x0C, 0x02, 0x3C], // w
+    [0x22, 0x14, 0x08, 0x14, 0x22], // x
+    [0x30, 0x0A, 0x0A, 0x0A, 0x3C], // y
+    [0x22, 0x26, 0x2A, 0x32, 0x22], // z
+    [0x00, 0x10, 0x6C, 0x82, 0x00], // {
+    [0x00, 0x00, 0xFE, 0x00, 0x00], // |
+    [0x00, 0x82, 0x6C, 0x10, 0x00], // }
+    [0x10, 0x20, 0x10, 0x08, 0x10], // ~
+];
+
+/// Glyph of `c` from `FONT`, `?` for characters it doesn't have.
+pub fn glyph(c: char) -> &'static [u8;5] {
+    let code = c as u32;
+    if code >= FONT_FIRST as u32 && code < FONT_FIRST as u32 + FONT.len() as u32 {
+        &FONT[(code - FONT_FIRST as u32) as usize]
+    } else {
+        &FONT[(b'?' - FONT_FIRST) as usize]
+    }
+}
+
+
+/// Supported panel sizes, width x height in pixels.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Geometry {
+    W128H32,
+    W128H64,
+    W96H16,
+    W64H48,
+}
+
+impl Geometry {
+    pub fn width(&self) -> u8 {
+        match *self {
+            Geometry::W128H32 | Geometry::W128H64 => 128,
+            Geometry::W96H16 => 96,
+            Geometry::W64H48 => 64,
+        }
+    }
+
+    pub fn height(&self) -> u8 {
+        match *self {
+            Geometry::W128H32 => 32,
+            Geometry::W128H64 => 64,
+            Geometry::W96H16 => 16,
+            Geometry::W64H48 => 48,
+        }
+    }
+
+    pub fn pages(&self) -> u8 {
+        self.height() / 8
+    }
+
+    /// First column of the controller RAM which is wired to the panel.
+    pub fn column_offset(&self) -> u8 {
+        match *self {
+            Geometry::W64H48 => 32,
+            _ => 0,
+        }
+    }
 
-const LCD_HEIGHT : u8 = 32;
+    /// Setting of `CMD_SETCOMPINS`, sequential for the panels with few rows.
+    fn com_pins(&self) -> u8 {
+        match *self {
+            Geometry::W128H32 | Geometry::W96H16 => 0x02,
+            Geometry::W128H64 | Geometry::W64H48 => 0x12,
+        }
+    }
+}
+
+static mut GEOMETRY : Geometry = Geometry::W128H32;
+
+/// Geometry the display was initialised with.
+pub fn geometry() -> Geometry {
+    unsafe { GEOMETRY }
+}
 
 
 // https://cdn-shop.adafruit.com/datasheets/SSD1306.pdf
@@ -122,7 +319,9 @@ pub fn write_control<'a, S>(i2c: &I2c<'a, S>, b : u8) -> I2CState where S : 'sta
     i2c.start_write_polling(ADDRESS).cont(|| {i2c.write_data(0x00)}).cont(|| {i2c.write_data(b)}).cont(|| {i2c.stop()})
 }
 
-pub fn sync_init<'a, S>(i2c: &I2c<'a, S>) where S : 'static + I2C {
+pub fn sync_init<'a, S>(i2c: &I2c<'a, S>, geometry: Geometry) where S : 'static + I2C {
+    unsafe { GEOMETRY = geometry; }
+
     let st = i2c.start_write_polling(ADDRESS)
         .cont(|| {i2c.write_data(0x00)})
         .cont(|| {i2c.write_data(CMD_DISPLAYOFF)})
@@ -131,7 +330,7 @@ pub fn sync_init<'a, S>(i2c: &I2c<'a, S>) where S : 'static + I2C {
         .cont(|| {write_control(&i2c, CMD_SETDISPLAYCLOCKDIV)})
         .cont(|| {write_control(&i2c, 0x80)})
         .cont(|| {write_control(&i2c, CMD_SETMULTIPLEX)})
-        .cont(|| {write_control(&i2c, LCD_HEIGHT - 1)})
+        .cont(|| {write_control(&i2c, geometry.height() - 1)})
         .cont(|| {write_control(&i2c, CMD_SETDISPLAYOFFSET)})
         .cont(|| {write_control(&i2c, 0x00)})
         .cont(|| {write_control(&i2c, CMD_SETSTARTLINE)})
@@ -141,7 +340,7 @@ pub fn sync_init<'a, S>(i2c: &I2c<'a, S>) where S : 'static + I2C {
         .cont(|| {write_control(&i2c, 0x14)})
         .cont(|| {write_control(&i2c, CMD_SETSEGREMAP | 0x01)})
         .cont(|| {write_control(&i2c, CMD_SETCOMPINS)})
-        .cont(|| {write_control(&i2c, 0x2)})
+        .cont(|| {write_control(&i2c, geometry.com_pins())})
         .cont(|| {write_control(&i2c, CMD_SETCONTRAST)})
         .cont(|| {write_control(&i2c, 0x8F)})
         .cont(|| {write_control(&i2c, CMD_SETPRECHARGE)})
@@ -161,177 +360,45 @@ pub fn sync_init<'a, S>(i2c: &I2c<'a, S>) where S : 'static + I2C {
 }
 
 
-const _BUF_LEN : usize = 512; 
-static mut _BUFFER : [u8;_BUF_LEN] = [0; _BUF_LEN];
-static mut BUFFER : CyclicBuffer<u8> = unsafe { CyclicBuffer { data: &mut _BUFFER, ptr: 0, len: 0} };
-
-pub enum LcdState {
-    Stopped,
-    Idle,
-    Data(u8),
-    Control(u8),
-    Control2(u8),
-}
-
-static mut LCD_STATE : LcdState = LcdState::Stopped;
+/// Most control bytes sent with a single `write_control_2`.
+const MAX_CONTROL : usize = 8;
 
 #[inline(never)]
 pub fn wait_buffer() {
-    unsafe {
-        while BUFFER.length() > _BUF_LEN / 2 {
-            ::rtfm::wfi(); 
-        }
-    }
+    i2cbus::wait_space();
 }
 
 use ::rtfm::{Resource, Threshold};
 
-pub fn write_data<'a, S>(t: &mut Threshold, i2c: &S, dat: &[u8]) 
+/// Queue display data, which is dropped and false returned if the bus queue
+/// is full.
+pub fn write_data<'a, S>(t: &mut Threshold, i2c: &S, dat: &[u8]) -> bool
 where 
     S : Resource<Data = I2C1>
 {
-    unsafe {
-        BUFFER.write(dat.len() as u8);
-
-        for el in dat.iter() {
-            while false == BUFFER.write(*el) { }
-        }
-    }
-
-    unsafe{
-        if let LcdState::Stopped = LCD_STATE {
-            i2c.claim(t, |i2c,_t| {
-                I2c(&*i2c).enable_start();
-            });
-            LCD_STATE = LcdState::Idle;
-        }
-    }
-
-    i2c.claim(t, |i2c,_t| {
-        I2c(&*i2c).listen();
-    });
+    i2cbus::write(t, i2c, ADDRESS, &[0x40], dat, None)
 }
 
-pub fn write_control_2<'a, S>(t: &mut Threshold, i2c: &S, dat: &[u8]) 
+/// Queue control bytes, each is sent with its own control byte prefix.
+pub fn write_control_2<'a, S>(t: &mut Threshold, i2c: &S, dat: &[u8]) -> bool
 where
     S : Resource<Data = I2C1>
 {
-    unsafe {
-        BUFFER.write(0x80 | dat.len() as u8);
-
-        for el in dat.iter() {
-            while false == BUFFER.write(*el) {::rtfm::wfi() }
-        }
+    let mut buf = [0x80; 2 * MAX_CONTROL];
+    let len = if dat.len() < MAX_CONTROL { dat.len() } else { MAX_CONTROL };
+    for (i, el) in dat[..len].iter().enumerate() {
+        buf[2 * i + 1] = *el;
     }
 
-    unsafe{
-        if let LcdState::Stopped = LCD_STATE {
-            i2c.claim(t, |i2c,_t| {
-                I2c(&*i2c).enable_start();
-            });
-            LCD_STATE = LcdState::Idle;
-        }
-    }
-
-    i2c.claim(t, |i2c,_t| {
-        I2c(&*i2c).listen();
-    });
+    i2cbus::write(t, i2c, ADDRESS, &buf[..2 * len], &[], None)
 }
 
-pub fn start_next<'a, S: I2C + Any>(i2c: I2cState<'a, S, Write>) {
-    unsafe {
-        let a = BUFFER.read();
-        if let Some(a) = a {
-            if a & 0x80 == 0 {
-                LCD_STATE = LcdState::Data(a);
-                i2c.write(0x40);
-                //iprintln!("dat {}", a);
-            } else {
-                let a = a & (!0x80);
-                LCD_STATE = LcdState::Control2(a);
-                i2c.write(0x80);
-                //iprintln!("ctrl {}", a);
-            }
-        } else {
-            ::rtfm::bkpt();
-        }
-    }
-}
-
-pub fn event_interrupt<'a, S>(i2c: &I2c<'a, S>) where S : 'static + I2C {
-    unsafe {
-        match i2c.get_state() {
-            I2cStateOptions::Started(s) => {
-                s.write_address(ADDRESS, false);
-
-                //iprintln!("st");
-            }, 
-            I2cStateOptions::CanWrite(w) => {
-                //iprintln!("wr");
-
-                match LCD_STATE {
-                    LcdState::Stopped => {
-                        // this should not happen
-                        ::rtfm::bkpt(); 
-                    }
-                    LcdState::Idle => {
-                        start_next(w);
-                        //iprintln!(itm, "idle {}", i2c.0.sr1.read().bits());
-                    },
-                    LcdState::Control(b) => {
-                        if b > 0 {
-                            w.write(0x80);
-                            LCD_STATE = LcdState::Control2(b);
-                        } else {
-                            //iprintln!("ectrl");
-                            start_next(w)
-                        }
-                    },
-                    LcdState::Control2(b) => {
-                        let d = BUFFER.read();
-                        if let Some(d) = d {
-                            //iprintln!("cb {}", d);
-                            w.write(d);
-                            LCD_STATE = LcdState::Control(b - 1);
-                        } else {
-                            //iprintln!("ctrle");
-                            w.suspend();
-                        }
-                    },
-                    LcdState::Data(b) => {
-                        // there is still data to write
-                        if b > 0 {
-                            let d = BUFFER.read();
-                            if let Some(d) = d {
-                                //iprintln!("db {}", d);
-                                w.write(d);
-                                LCD_STATE = LcdState::Data(b - 1);
-                            } else {
-                                //iprintln!("dbe");
-                                // data to write but buffer is empty
-                                w.suspend()
-                            }
-                        } else {
-                            //iprintln!("nmd");
-                            if let Some(d) = BUFFER.peak() {
-                                if d & 0x80 == 0 {
-                                    // if we have further data just queue it
-                                    BUFFER.read();
-                                    LCD_STATE = LcdState::Data(d)
-                                } else {
-                                    i2c.enable_start();
-                                    LCD_STATE = LcdState::Idle;
-                                } 
-                            } else {
-                                //iprintln!("stopped");
-                                w.stop();
-                                LCD_STATE = LcdState::Stopped;
-                            }
-                        }
-                    }
-                }
-            }
-            _ => ()
-        }
-    }
+/// Queue new contrast and precharge periods, phase 1 in the low and phase 2
+/// in the high nibble of `precharge`. Returns false if the commands were not
+/// queued.
+pub fn set_contrast<'a, S>(t: &mut Threshold, i2c: &S, contrast: u8, precharge: u8) -> bool
+where
+    S : Resource<Data = I2C1>
+{
+    write_control_2(t, i2c, &[CMD_SETCONTRAST, contrast, CMD_SETPRECHARGE, precharge])
 }
\ No newline at end of file
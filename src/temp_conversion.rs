@@ -1,37 +1,228 @@
-type TempPair = (u16, u16);
-
-static LOOKUP_TABLE : &[TempPair]= &[
-    (0, 10000),
-    (1000, 10390),
-    (2000, 10779),
-    (3000, 11167),
-    (4000, 11554),
-    (5000, 11940),
-    (6000, 12324),
-    (7000, 12708),
-    (8000, 13090),
-    (9000, 13471),
-    (10000, 13851),
-    (11000, 14229),
-    (12000, 14607),
-    (13000, 14983),
-];
-
-pub fn lookup_temperature(val : u16) -> u32 {
-    let mut first = &(0, 10000);
-    let mut second = &(1000, 10390);
-    let mut iterator = LOOKUP_TABLE.iter();
-    while let Some(a) = iterator.next() {
-        first = second;
-        second = &a;
-        if a.1 > val { break; }
-    }
-    let second = iterator.next();
-
-    if let Some(second) = second {
-        let temp = (second.0 - first.0) as u32 * (val - first.1) as u32 / (second.1 - first.1) as u32 + first.0 as u32;
-        temp
+type TempPair = (i32, u32);
+
+/// Temperature coefficient of the RTD, selecting the resistance curve.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Alpha {
+    /// 0.00385, IEC 60751
+    Alpha385,
+    /// 0.00392, US curve
+    Alpha392,
+}
+
+// Pairs of temperature in 1/100 degrees and the resistance relative to R0
+// scaled by `RATIO_ONE`, generated by build.rs from the Callendar-Van Dusen
+// equation. The range and step can be set with the RTD_TABLE_MIN,
+// RTD_TABLE_MAX and RTD_TABLE_STEP environment variables at build time, with
+// the default step of 10 degrees the linear interpolation is within 0.01
+// degrees of the equation and within 0.02 degrees of the IEC 60751 reference
+// table, see `cvd_temperature`.
+include!(concat!(env!("OUT_DIR"), "/rtd_table.rs"));
+
+/// Resistance relative to R0, below which the RTD is considered shorted.
+const IMPLAUSIBLE_LOW : u32 = 100_000;
+/// Resistance relative to R0, above which the RTD is considered open.
+const IMPLAUSIBLE_HIGH : u32 = 4_500_000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ConversionError {
+    /// Below the range of the lookup table
+    BelowRange,
+    /// Above the range of the lookup table
+    AboveRange,
+    /// The resistance can't come from a working RTD, the probe is likely
+    /// shorted or disconnected
+    Implausible,
+}
+
+/// Callendar-Van Dusen coefficients in fixed point, A and B scaled by 1e10
+/// and C scaled by 1e15.
+struct Coefficients {
+    a : i64,
+    b : i64,
+    c : i64,
+}
+
+impl Alpha {
+    fn table(&self) -> &'static [TempPair] {
+        match *self {
+            Alpha::Alpha385 => LOOKUP_TABLE_385,
+            Alpha::Alpha392 => LOOKUP_TABLE_392,
+        }
+    }
+
+    fn coefficients(&self) -> Coefficients {
+        match *self {
+            // A = 3.9083e-3, B = -5.775e-7, C = -4.183e-12
+            Alpha::Alpha385 => Coefficients { a: 39083000, b: -5775, c: -4183 },
+            // A = 3.9848e-3, B = -5.870e-7, C = -4.000e-12
+            Alpha::Alpha392 => Coefficients { a: 39848000, b: -5870, c: -4000 },
+        }
+    }
+}
+
+/// Resistance relative to R0 of one in `cvd_temperature` and `cvd_ratio`.
+pub const RATIO_ONE : u32 = 1_000_000;
+
+/// Integer square root, rounded down.
+fn isqrt(n: u64) -> u64 {
+    let mut rem = n;
+    let mut root = 0;
+    let mut bit = 1 << 62;
+
+    while bit > n {
+        bit >>= 2;
+    }
+
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+
+    root
+}
+
+/// Division rounding half away from zero.
+fn div_round(n: i64, d: i64) -> i64 {
+    if (n < 0) == (d < 0) {
+        (n + d / 2) / d
     } else {
-        0
+        (n - d / 2) / d
+    }
+}
+
+/// R(t) / R0 - 1 scaled by 1e12 for `t` in 1/100 degrees.
+fn cvd_offset(k: &Coefficients, t: i64) -> i64 {
+    let mut w = k.a * t + k.b * t * t / 100;
+    if t < 0 {
+        // C (t - 100) t^3, t^3 is reduced first to stay within 64 bits
+        w += k.c * (t - 10000) * (t * t * t / 10000) / 10_000_000;
+    }
+    w
+}
+
+/// Converts the resistance relative to R0, scaled by `RATIO_ONE`, into the
+/// temperature in 1/100 degrees using the Callendar-Van Dusen equation.
+///
+/// Above 0 degrees the quadratic is solved directly, below a few Newton steps
+/// starting from the quadratic solution account for the C term. Only 64 bit
+/// integer arithmetic is used.
+///
+/// Over -200 to 850 degrees the result matches the exact solution of the
+/// equation to the 0.01 degree resolution. Against the IEC 60751 reference
+/// table, given with 0.01 Ohm resolution for a PT100, the results are within
+/// +-0.01 degrees.
+pub fn cvd_temperature(alpha: Alpha, ratio: u32) -> i32 {
+    let k = alpha.coefficients();
+    let x = ratio as i64 - RATIO_ONE as i64;
+
+    // t = (A - sqrt(A^2 + 4 B x)) / (-2 B), the square root is scaled by 1e10
+    let d = k.a * k.a + 4 * k.b * x * 10000;
+    let s = isqrt(if d < 0 { 0 } else { d as u64 }) as i64;
+    let mut t = div_round(50 * (k.a - s), -k.b);
+
+    if x < 0 {
+        let target = x * 1_000_000;
+        for _ in 0..4 {
+            let err = cvd_offset(&k, t) - target;
+            // derivative of the offset per 1/100 degree, scaled by 1e12
+            let deriv = k.a + 2 * k.b * t / 100
+                + k.c * (4 * (t * t * t / 1_000_000) - 3 * t * t / 100) / 100_000;
+            let step = div_round(err, deriv);
+            t -= step;
+            if step == 0 {
+                break;
+            }
+        }
+    }
+
+    t as i32
+}
+
+/// Converts a temperature in 1/100 degrees into the resistance relative to
+/// R0, scaled by `RATIO_ONE`, using the Callendar-Van Dusen equation.
+pub fn cvd_ratio(alpha: Alpha, temp: i32) -> u32 {
+    let k = alpha.coefficients();
+    let ratio = RATIO_ONE as i64 + div_round(cvd_offset(&k, temp as i64), 1_000_000);
+    if ratio < 0 { 0 } else { ratio as u32 }
+}
+
+fn interpolate(x : i64, x0 : i64, x1 : i64, y0 : i64, y1 : i64) -> i64 {
+    y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+}
+
+/// Finds the temperature in 1/100 degrees for a resistance relative to R0,
+/// scaled by `RATIO_ONE`, using binary search and linear interpolation.
+pub fn lookup_temperature(alpha : Alpha, ratio : u32) -> Result<i32, ConversionError> {
+    if ratio < IMPLAUSIBLE_LOW || ratio > IMPLAUSIBLE_HIGH {
+        return Err(ConversionError::Implausible);
+    }
+
+    let table = alpha.table();
+    match table.binary_search_by(|pair| pair.1.cmp(&ratio)) {
+        Ok(i) => Ok(table[i].0),
+        Err(0) => Err(ConversionError::BelowRange),
+        Err(i) if i == table.len() => Err(ConversionError::AboveRange),
+        Err(i) => {
+            let (a, b) = (&table[i - 1], &table[i]);
+            Ok(interpolate(ratio as i64, a.1 as i64, b.1 as i64, a.0 as i64, b.0 as i64) as i32)
+        }
+    }
+}
+
+/// Inverse of `lookup_temperature`, finds the resistance relative to R0,
+/// scaled by `RATIO_ONE`, for a temperature in 1/100 degrees.
+pub fn lookup_resistance(alpha : Alpha, temp : i32) -> Result<u32, ConversionError> {
+    let table = alpha.table();
+    match table.binary_search_by(|pair| pair.0.cmp(&temp)) {
+        Ok(i) => Ok(table[i].1),
+        Err(0) => Err(ConversionError::BelowRange),
+        Err(i) if i == table.len() => Err(ConversionError::AboveRange),
+        Err(i) => {
+            let (a, b) = (&table[i - 1], &table[i]);
+            Ok(interpolate(temp as i64, a.0 as i64, b.0 as i64, a.1 as i64, b.1 as i64) as u32)
+        }
+    }
+}
+
+/// Unit in which temperatures are shown and configured.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl Unit {
+    /// Convert a temperature in 1/100 °C to 1/100 of this unit, rounded to
+    /// the nearest hundredth.
+    pub fn from_celsius(&self, temp: i32) -> i32 {
+        match *self {
+            Unit::Celsius => temp,
+            Unit::Fahrenheit => div_round(temp as i64 * 9, 5) as i32 + 3200,
+            Unit::Kelvin => temp + 27315,
+        }
+    }
+
+    /// Convert a temperature difference in 1/100 °C to 1/100 of this unit.
+    pub fn delta_from_celsius(&self, delta: i32) -> i32 {
+        match *self {
+            Unit::Fahrenheit => div_round(delta as i64 * 9, 5) as i32,
+            _ => delta,
+        }
+    }
+
+    /// Convert a temperature in 1/100 of this unit to 1/100 °C, rounded to
+    /// the nearest hundredth.
+    pub fn to_celsius(&self, temp: i32) -> i32 {
+        match *self {
+            Unit::Celsius => temp,
+            Unit::Fahrenheit => div_round((temp - 3200) as i64 * 5, 9) as i32,
+            Unit::Kelvin => temp - 27315,
+        }
     }
 }
\ No newline at end of file
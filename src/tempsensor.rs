@@ -1,4 +1,3 @@
-
 #[macro_use]
 #[allow(unused_imports)]
 use debug;
@@ -6,52 +5,434 @@ use stm32;
 use spi;
 use cortex_m;
 
+use core::marker::PhantomData;
+use core::{mem, ptr};
 use spi::{Spi, SPIResult, SpiStateOptions};
-//use rtfm::{Resource, Threshold};
+use rtfm::{Resource, Threshold};
 use tslib::gpio::{GpioPinDefault, Pin12, Pin13, Pin14, Pin15};
+use temp_conversion;
+use temp_conversion::{Alpha, ConversionError};
+use calibration::Calibration;
+use sensor::{TemperatureSensor, Temperature, Measurement, Reading, SensorError, Status};
+use thermocouple;
 
+/// Describes the RTD and the reference resistor it is measured against.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ProbeType {
+    /// Nominal resistance at 0 degrees in mOhm
+    pub r0 : u32,
+    /// Reference resistor of the board in mOhm
+    pub r_ref : u32,
+    pub alpha : Alpha,
+}
 
-pub fn init_temp<'a>(
-    spi2: &'a stm32::SPI2,
-    pinb12: GpioPinDefault<'a, stm32::GPIOB, Pin12>, 
-    pinb13: GpioPinDefault<'a, stm32::GPIOB, Pin13>,
-    pinb14: GpioPinDefault<'a, stm32::GPIOB, Pin14>, 
-    pinb15: GpioPinDefault<'a, stm32::GPIOB, Pin15>) {
+/// PT100 on a board with a 430 Ohm reference resistor (measured 432.34 Ohm).
+pub const PT100 : ProbeType = ProbeType {
+    r0: 100_000,
+    r_ref: 432_340,
+    alpha: Alpha::Alpha385,
+};
 
-    let pinb12 = pinb12.set_output_10MHz().set_alt_output_push_pull(); // NSS
-    let pinb13 = pinb13.set_output_10MHz().set_alt_output_push_pull(); // SCK
-    let pinb14 = pinb14.set_input().set_floating_input(); // MISO
-    let pinb15 = pinb15.set_output_10MHz().set_alt_output_push_pull(); // MISO
+/// PT1000 on a board with a 4.3 kOhm reference resistor.
+pub const PT1000 : ProbeType = ProbeType {
+    r0: 1_000_000,
+    r_ref: 4_300_000,
+    alpha: Alpha::Alpha385,
+};
 
-    let spi2 = Spi(spi2);
-    let r = spi2.start_init();
+impl ProbeType {
+    /// Convert a 15 bit RTD code into the resistance in mOhm.
+    pub fn resistance(&self, code: u16) -> u32 {
+        ((code as u64 * self.r_ref as u64) >> 15) as u32
+    }
 
-    let ports = r.set_ports(pinb12, pinb13, pinb14, pinb15);
+    /// Convert a resistance in mOhm into the 15 bit RTD code.
+    pub fn code(&self, res: u32) -> u16 {
+        let code = ((res as u64) << 15) / self.r_ref as u64;
+        if code > 0x7FFF { 0x7FFF } else { code as u16 }
+    }
 
-    spi2.complete_init(ports);
+    /// Resistance relative to R0, scaled by `temp_conversion::RATIO_ONE`.
+    fn ratio(&self, res: u32) -> u32 {
+        (res as u64 * temp_conversion::RATIO_ONE as u64 / self.r0 as u64) as u32
+    }
 
-    spi2.listen(false, true);
+    /// Convert a 15 bit RTD code into the temperature in 1/100 degrees.
+    pub fn temperature(&self, code: u16) -> Result<i32, ConversionError> {
+        temp_conversion::lookup_temperature(self.alpha, self.ratio(self.resistance(code)))
+    }
+
+    /// Convert a temperature in 1/100 degrees into the 15 bit RTD code.
+    pub fn code_for_temperature(&self, temp: i32) -> u16 {
+        let ratio = temp_conversion::cvd_ratio(self.alpha, temp);
+        self.code((ratio as u64 * self.r0 as u64 / temp_conversion::RATIO_ONE as u64) as u32)
+    }
+}
+
+// MAX31865 register addresses, the write address is the read address with
+// the MSB set
+pub const REG_CONFIG : u8 = 0x00;
+pub const REG_RTD_MSB : u8 = 0x01;
+pub const REG_RTD_LSB : u8 = 0x02;
+pub const REG_HIGH_FAULT_MSB : u8 = 0x03;
+pub const REG_HIGH_FAULT_LSB : u8 = 0x04;
+pub const REG_LOW_FAULT_MSB : u8 = 0x05;
+pub const REG_LOW_FAULT_LSB : u8 = 0x06;
+pub const REG_FAULT_STATUS : u8 = 0x07;
+pub const REG_WRITE : u8 = 0x80;
+
+const CONF_VBIAS : u8 = 0b10000000;
+const CONF_AUTO : u8 = 0b01000000;
+const CONF_ONE_SHOT : u8 = 0b00100000;
+const CONF_3WIRE : u8 = 0b00010000;
+const CONF_FAULT_CYCLE : u8 = 0b00001100;
+const CONF_FAULT_CLEAR : u8 = 0b00000010;
+const CONF_50HZ : u8 = 0b00000001;
+
+/// Bits of the configuration register which read back what was written.
+/// 1-shot and fault clear are self clearing and the fault detection cycle
+/// bits report the progress of the cycle instead.
+const CONF_STABLE_MASK : u8 = CONF_VBIAS | CONF_AUTO | CONF_3WIRE | CONF_50HZ;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ConversionMode {
+    /// Conversions only happen when requested using 1-shot.
+    NormallyOff,
+    /// Continuous conversions at the filter rate (50/60 Hz).
+    Auto,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Wires {
+    /// 2-wire or 4-wire RTD connection
+    TwoOrFour,
+    /// 3-wire RTD connection
+    Three,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FaultCycle {
+    /// No fault detection cycle is running
+    None,
+    /// Fault detection with automatic delay
+    Automatic,
+    /// Start of a fault detection cycle with manual delay
+    ManualStart,
+    /// Finish of a fault detection cycle with manual delay
+    ManualFinish,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Filter {
+    Hz60,
+    Hz50,
 }
 
+/// Contents of the MAX31865 configuration register.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Config {
+    pub vbias : bool,
+    pub mode : ConversionMode,
+    pub one_shot : bool,
+    pub wires : Wires,
+    pub fault_cycle : FaultCycle,
+    pub fault_clear : bool,
+    pub filter : Filter,
+}
+
+impl Config {
+    /// The power on configuration of the converter (all bits cleared).
+    pub fn new() -> Config {
+        Config {
+            vbias: false,
+            mode: ConversionMode::NormallyOff,
+            one_shot: false,
+            wires: Wires::TwoOrFour,
+            fault_cycle: FaultCycle::None,
+            fault_clear: false,
+            filter: Filter::Hz60,
+        }
+    }
+
+    pub fn vbias(mut self, vbias: bool) -> Config {
+        self.vbias = vbias;
+        self
+    }
+
+    pub fn mode(mut self, mode: ConversionMode) -> Config {
+        self.mode = mode;
+        self
+    }
+
+    pub fn one_shot(mut self, one_shot: bool) -> Config {
+        self.one_shot = one_shot;
+        self
+    }
+
+    pub fn wires(mut self, wires: Wires) -> Config {
+        self.wires = wires;
+        self
+    }
+
+    pub fn fault_cycle(mut self, fault_cycle: FaultCycle) -> Config {
+        self.fault_cycle = fault_cycle;
+        self
+    }
+
+    pub fn fault_clear(mut self, fault_clear: bool) -> Config {
+        self.fault_clear = fault_clear;
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Config {
+        self.filter = filter;
+        self
+    }
+
+    /// Encode the configuration into the register value.
+    pub fn bits(&self) -> u8 {
+        let mut b = 0;
+        if self.vbias { b |= CONF_VBIAS; }
+        if let ConversionMode::Auto = self.mode { b |= CONF_AUTO; }
+        if self.one_shot { b |= CONF_ONE_SHOT; }
+        if let Wires::Three = self.wires { b |= CONF_3WIRE; }
+        b |= match self.fault_cycle {
+            FaultCycle::None => 0b0000,
+            FaultCycle::Automatic => 0b0100,
+            FaultCycle::ManualStart => 0b1000,
+            FaultCycle::ManualFinish => 0b1100,
+        };
+        if self.fault_clear { b |= CONF_FAULT_CLEAR; }
+        if let Filter::Hz50 = self.filter { b |= CONF_50HZ; }
+        b
+    }
+
+    /// Decode a value read from the configuration register.
+    pub fn from_bits(b: u8) -> Config {
+        Config {
+            vbias: b & CONF_VBIAS != 0,
+            mode: if b & CONF_AUTO != 0 { ConversionMode::Auto } else { ConversionMode::NormallyOff },
+            one_shot: b & CONF_ONE_SHOT != 0,
+            wires: if b & CONF_3WIRE != 0 { Wires::Three } else { Wires::TwoOrFour },
+            fault_cycle: match b & CONF_FAULT_CYCLE {
+                0b0000 => FaultCycle::None,
+                0b0100 => FaultCycle::Automatic,
+                0b1000 => FaultCycle::ManualStart,
+                _ => FaultCycle::ManualFinish,
+            },
+            fault_clear: b & CONF_FAULT_CLEAR != 0,
+            filter: if b & CONF_50HZ != 0 { Filter::Hz50 } else { Filter::Hz60 },
+        }
+    }
+
+    /// Check a value read back from the configuration register against this
+    /// configuration, ignoring the self clearing bits.
+    pub fn verify(&self, read_back: u8) -> bool {
+        self.bits() & CONF_STABLE_MASK == read_back & CONF_STABLE_MASK
+    }
+
+    /// Configuration starting a fault detection cycle with automatic delay.
+    ///
+    /// Requires the bias to be on and conversions to be off. Once written, poll
+    /// the configuration register until `fault_cycle_done` returns true (about
+    /// 550 us) and then read the fault status register.
+    pub fn automatic_fault_detection(&self) -> Config {
+        self.vbias(true)
+            .mode(ConversionMode::NormallyOff)
+            .one_shot(false)
+            .fault_cycle(FaultCycle::Automatic)
+            .fault_clear(false)
+    }
+
+    /// First step of a fault detection cycle with manual delay.
+    ///
+    /// After writing this wait at least 5 time constants of the input filter
+    /// before writing `manual_fault_detection_finish`.
+    pub fn manual_fault_detection_start(&self) -> Config {
+        self.vbias(true)
+            .mode(ConversionMode::NormallyOff)
+            .one_shot(false)
+            .fault_cycle(FaultCycle::ManualStart)
+            .fault_clear(false)
+    }
+
+    /// Second step of a fault detection cycle with manual delay, completion is
+    /// polled like for the automatic cycle.
+    pub fn manual_fault_detection_finish(&self) -> Config {
+        self.manual_fault_detection_start()
+            .fault_cycle(FaultCycle::ManualFinish)
+    }
+
+    /// This configuration with the fault status clear bit set. 1-shot and the
+    /// fault detection cycle bits have to be zero when clearing faults.
+    pub fn clear_faults(&self) -> Config {
+        self.one_shot(false)
+            .fault_cycle(FaultCycle::None)
+            .fault_clear(true)
+    }
+}
+
+/// Reads of the configuration register before a fault detection cycle is
+/// given up, each read takes several microseconds so this is well beyond the
+/// 550 us of the automatic cycle.
+const FAULT_CYCLE_POLLS : u32 = 1000;
+
+/// Returns true once the fault detection cycle reported in the configuration
+/// register `conf` has completed.
+pub fn fault_cycle_done(conf: u8) -> bool {
+    conf & CONF_FAULT_CYCLE == 0
+}
+
+/// Returns true if the fault bit in the RTD LSB register is set.
+pub fn rtd_fault(lsb: u8) -> bool {
+    lsb & 0x01 != 0
+}
+
+/// Faults reported by the fault status register.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// RTD resistance above the high fault threshold
+    RtdHighThreshold = 0b10000000,
+    /// RTD resistance below the low fault threshold
+    RtdLowThreshold = 0b01000000,
+    /// REFIN- > 0.85 x VBIAS
+    RefInHigh = 0b00100000,
+    /// REFIN- < 0.85 x VBIAS, FORCE- open
+    RefInLow = 0b00010000,
+    /// RTDIN- < 0.85 x VBIAS, FORCE- open
+    RtdInLow = 0b00001000,
+    /// Overvoltage or undervoltage on any of the inputs
+    OverUnderVoltage = 0b00000100,
+}
+
+static FAULTS : [Fault; 6] = [
+    Fault::RtdHighThreshold,
+    Fault::RtdLowThreshold,
+    Fault::RefInHigh,
+    Fault::RefInLow,
+    Fault::RtdInLow,
+    Fault::OverUnderVoltage,
+];
+
+impl Fault {
+    /// A short numeric code (1-6) for showing the fault on the display.
+    pub fn code(&self) -> u8 {
+        match *self {
+            Fault::RtdHighThreshold => 1,
+            Fault::RtdLowThreshold => 2,
+            Fault::RefInHigh => 3,
+            Fault::RefInLow => 4,
+            Fault::RtdInLow => 5,
+            Fault::OverUnderVoltage => 6,
+        }
+    }
+}
+
+/// Contents of the fault status register.
+#[derive(Clone, Copy, PartialEq)]
+pub struct FaultStatus(pub u8);
+
+impl FaultStatus {
+    pub fn is_set(&self, fault: Fault) -> bool {
+        self.0 & (fault as u8) != 0
+    }
+
+    pub fn any(&self) -> bool {
+        self.first().is_some()
+    }
+
+    /// The most significant fault which is set.
+    pub fn first(&self) -> Option<Fault> {
+        FAULTS.iter().find(|f| self.is_set(**f)).map(|f| *f)
+    }
+}
+
+/// A GPIO pin used as software chip select, for sharing the bus between
+/// several converters.
+#[derive(Clone, Copy)]
+pub struct ChipSelect {
+    port : *const stm32::gpioa::RegisterBlock,
+    pin : u8,
+}
+
+impl ChipSelect {
+    /// Configure `pin` of `port` as a 10 MHz push-pull output, initially
+    /// deasserted.
+    pub fn new(port: &stm32::gpioa::RegisterBlock, pin: u8) -> ChipSelect {
+        let cs = ChipSelect {
+            port: port as *const _,
+            pin: pin,
+        };
+        cs.deassert();
+
+        // MODE = 01 (output 10 MHz), CNF = 00 (general purpose push-pull)
+        let shift = (pin as u32 % 8) * 4;
+        let mask = !(0xF << shift);
+        let mode = 0b0001 << shift;
+        unsafe {
+            if pin < 8 {
+                port.crl.modify(|r, w| w.bits((r.bits() & mask) | mode));
+            } else {
+                port.crh.modify(|r, w| w.bits((r.bits() & mask) | mode));
+            }
+        }
+
+        cs
+    }
+
+    fn port(&self) -> &stm32::gpioa::RegisterBlock {
+        unsafe { &*self.port }
+    }
+
+    /// Drive the pin low to select the device.
+    pub fn assert(&self) {
+        self.port().bsrr.write(|w| unsafe { w.bits(1 << (self.pin as u32 + 16)) });
+    }
+
+    pub fn deassert(&self) {
+        self.port().bsrr.write(|w| unsafe { w.bits(1 << self.pin as u32) });
+    }
+}
+
+/// Switch SPI2 from the hardware NSS output to software slave management, so
+/// that `ChipSelect` pins can be used instead. NSS (PB12) can then be used as
+/// a chip select as well.
+pub fn use_software_nss(spi2: &stm32::SPI2) {
+    spi2.cr2.modify(|_, w| w.ssoe().clear_bit());
+    spi2.cr1.modify(|_, w| w.ssm().set_bit().ssi().set_bit());
+}
+
+/// Maximum number of data bytes in a single transaction, enough to cover the
+/// whole register map using the address auto-increment.
+pub const MAX_BURST : usize = 8;
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum SpiState {
     Idle,
-    ReadFirst,
-    ReadSecond,
-    WriteFirst,
-    WriteSecond,
+    Address,
+    Transfer,
     Finished
 }
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum SpiAction {
     Read,
-    Write(u8),
+    Write,
 }
 
+/// An interrupt driven SPI transaction consisting of a register address
+/// followed by up to `MAX_BURST` data bytes, all within one chip select
+/// assertion.
 pub struct SpiResource {
     pub action : SpiAction,
     pub state : SpiState,
 
-    pub result : u8,
+    buffer : [u8; MAX_BURST],
+    len : usize,
+    pos : usize,
+    cs : Option<ChipSelect>,
+    /// CPOL and CPHA to restore after a frame read
+    saved_mode : Option<(bool, bool)>,
 }
 
 
@@ -59,25 +440,120 @@ pub static mut SPI_RES: SpiResource = SpiResource {
     action: SpiAction::Read,
     state: SpiState::Idle,
 
-    result: 0,
+    buffer: [0; MAX_BURST],
+    len: 0,
+    pos: 0,
+    cs: None,
+    saved_mode: None,
 }; 
 
 impl SpiResource {
+    /// Select the chip select used by the following transactions, `None` uses
+    /// the hardware NSS output.
+    pub fn select(&mut self, cs: Option<ChipSelect>) {
+        self.cs = cs;
+    }
+
     pub fn start_read<'a, S : spi::SPI + 'static>(&mut self, reg: u8, spi : &Spi<'a, S>) {
-        spi.enable();
-        //rtfm::bkpt();
-        spi.send(reg);
+        self.start_read_burst(reg, 1, spi);
+    }
 
-        self.state = SpiState::ReadFirst;
+    /// Start reading `len` consecutive registers beginning at `reg`. Once
+    /// finished the data can be retrieved using `result_into`.
+    pub fn start_read_burst<'a, S : spi::SPI + 'static>(&mut self, reg: u8, len: usize, spi : &Spi<'a, S>) {
         self.action = SpiAction::Read;
+        self.len = if len > MAX_BURST { MAX_BURST } else { len };
+        self.start(reg, spi);
     }
 
     pub fn start_write<'a, S : spi::SPI + 'static>(&mut self, reg: u8, val: u8, spi : &Spi<'a, S>) {
+        self.start_write_burst(reg, &[val], spi);
+    }
+
+    /// Start writing `data` to consecutive registers beginning at `reg`.
+    pub fn start_write_burst<'a, S : spi::SPI + 'static>(&mut self, reg: u8, data: &[u8], spi : &Spi<'a, S>) {
+        self.action = SpiAction::Write;
+        self.len = if data.len() > MAX_BURST { MAX_BURST } else { data.len() };
+        self.buffer[..self.len].copy_from_slice(&data[..self.len]);
+        self.start(reg, spi);
+    }
+
+    /// Start reading `len` bytes without sending an address first, for
+    /// devices which only have a single output frame.
+    ///
+    /// Such devices (the MAX31855) change their output on the falling edge of
+    /// SCK, so the frame is clocked in SPI mode 0, sampling on the rising
+    /// edge. The mode of the MAX31865 is restored by `restore_mode`.
+    pub fn start_read_frame<'a>(&mut self, len: usize, spi : &Spi<'a, stm32::SPI2>) {
+        let cr1 = spi.0.cr1.read();
+        self.saved_mode = Some((cr1.cpol().bit_is_set(), cr1.cpha().bit_is_set()));
+        spi.0.cr1.modify(|_, w| w.cpol().clear_bit().cpha().clear_bit());
+
+        self.action = SpiAction::Read;
+        self.len = if len > MAX_BURST { MAX_BURST } else { len };
+        self.pos = 0;
+        self.state = SpiState::Transfer;
+
+        if let Some(cs) = self.cs {
+            cs.assert();
+        }
+        spi.enable();
+        spi.send(0);
+    }
+
+    fn start<'a, S : spi::SPI + 'static>(&mut self, reg: u8, spi : &Spi<'a, S>) {
+        self.pos = 0;
+        self.state = SpiState::Address;
+
+        if let Some(cs) = self.cs {
+            cs.assert();
+        }
         spi.enable();
         spi.send(reg);
+    }
+
+    /// Switch back to the SPI mode used before `start_read_frame`, once the
+    /// frame has been read.
+    pub fn restore_mode<'a>(&mut self, spi : &Spi<'a, stm32::SPI2>) {
+        if self.busy() {
+            return;
+        }
+        if let Some((cpol, cpha)) = self.saved_mode.take() {
+            spi.0.cr1.modify(|_, w| w.cpol().bit(cpol).cpha().bit(cpha));
+        }
+    }
+
+    /// True while a transaction is in progress.
+    pub fn busy(&self) -> bool {
+        match self.state {
+            SpiState::Address | SpiState::Transfer => true,
+            _ => false
+        }
+    }
+
+    /// Wait for the current transaction to finish. The SPI interrupt must be
+    /// able to preempt the caller, so never call this from within a claim.
+    #[inline(never)]
+    pub fn wait(&self) {
+        loop {
+            match unsafe { ptr::read_volatile(&self.state) } {
+                SpiState::Address | SpiState::Transfer => ::rtfm::wfi(),
+                _ => break
+            }
+        }
+    }
 
-        self.state = SpiState::ReadFirst;
-        self.action = SpiAction::Write(val);
+    /// The first byte received by the last read.
+    pub fn result(&self) -> u8 {
+        self.buffer[0]
+    }
+
+    /// Copy the bytes received by the last read into `buf`, returns the number
+    /// of bytes copied.
+    pub fn result_into(&self, buf: &mut [u8]) -> usize {
+        let len = if buf.len() < self.len { buf.len() } else { self.len };
+        buf[..len].copy_from_slice(&self.buffer[..len]);
+        len
     }
 
     pub fn read<'a, S : spi::SPI + 'static>(&mut self, spi : &Spi<'a, S>) -> Option<u8> {
@@ -93,6 +569,22 @@ impl SpiResource {
         }
     }
 
+    fn send_next<'a, S : spi::SPI + 'static>(&mut self, spi : &Spi<'a, S>) {
+        if self.pos < self.len {
+            match self.action {
+                SpiAction::Read => spi.send(0),
+                SpiAction::Write => spi.send(self.buffer[self.pos]),
+            };
+            self.state = SpiState::Transfer;
+        } else {
+            spi.disable();
+            if let Some(cs) = self.cs {
+                cs.deassert();
+            }
+            self.state = SpiState::Finished;
+        }
+    }
+
     pub fn process_int<'a, S : spi::SPI + 'static>(&mut self, spi : &Spi<'a, S>) {
         let state = spi.get_state();
 
@@ -100,17 +592,16 @@ impl SpiResource {
             SpiStateOptions::CanRead(read) => {
                 let b = read.read();
                 match self.state {
-                    SpiState::ReadFirst => {
-                        match self.action {
-                            SpiAction::Read => spi.send(0),
-                            SpiAction::Write(a) => spi.send(a),
-                        };
-                        self.state = SpiState::ReadSecond
+                    SpiState::Address => {
+                        // the byte clocked in with the address is meaningless
+                        self.send_next(spi);
                     }
-                    SpiState::ReadSecond => {
-                        self.result = b;
-                        spi.disable();
-                        self.state = SpiState::Finished
+                    SpiState::Transfer => {
+                        if let SpiAction::Read = self.action {
+                            self.buffer[self.pos] = b;
+                        }
+                        self.pos += 1;
+                        self.send_next(spi);
                     }
                     _ => {}
                 }
@@ -121,4 +612,841 @@ impl SpiResource {
             _ => {}
         }
     }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Error {
+    /// The configuration register read back a different value
+    ConfigMismatch(u8),
+    /// The fault threshold registers read back different values
+    ThresholdMismatch,
+    /// The fault bit of the RTD register was set, read the fault status
+    RtdFault,
+    /// The fault detection cycle did not complete
+    FaultCycleTimeout,
+}
+
+/// Run a single transaction on `SPI_RES` and wait for it to finish, returning
+/// the first byte read.
+pub fn transfer_sync<R, F>(t: &mut Threshold, spi: &R, cs: Option<ChipSelect>, f: F) -> u8
+where
+    R : Resource<Data = stm32::SPI2>,
+    F : FnOnce(&mut SpiResource, &Spi<stm32::SPI2>)
+{
+    spi.claim(t, |spi, _t| {
+        let spi = Spi(&*spi);
+        unsafe {
+            SPI_RES.select(cs);
+            f(&mut SPI_RES, &spi);
+        }
+    });
+
+    unsafe {
+        SPI_RES.wait();
+        SPI_RES.result()
+    }
+}
+
+/// A fault threshold tripped by the converter.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Alarm {
+    /// The RTD went above the high fault threshold
+    High,
+    /// The RTD went below the low fault threshold
+    Low,
+}
+
+/// The converter has not been configured yet.
+pub struct Unconfigured;
+/// The converter is configured and the bias voltage is on.
+pub struct Configured;
+/// The converter is running either a 1-shot or automatic conversions.
+pub struct Converting;
+/// The converter is configured but the bias voltage is off.
+pub struct Standby;
+
+/// MAX31865 RTD to digital converter on SPI2.
+///
+/// The state parameter tracks the converter setup, so that the RTD can only be
+/// read once conversions have been started, which in turn requires the bias
+/// voltage to be switched on by `configure`.
+///
+/// Transitions between states block until the transaction has completed and
+/// are meant to be called from `idle`. Reading the RTD is interrupt driven
+/// using `SPI_RES`.
+pub struct Max31865<State> {
+    conf : Config,
+    probe : ProbeType,
+    cs : Option<ChipSelect>,
+    alarm : Option<fn(Alarm)>,
+    _state : PhantomData<State>,
+}
+
+impl<State> Max31865<State> {
+    fn into_state<T>(self, conf: Config) -> Max31865<T> {
+        Max31865 {
+            conf: conf,
+            probe: self.probe,
+            cs: self.cs,
+            alarm: self.alarm,
+            _state: PhantomData,
+        }
+    }
+
+    /// The configuration last written to the converter.
+    pub fn config(&self) -> Config {
+        self.conf
+    }
+
+    pub fn probe_type(&self) -> ProbeType {
+        self.probe
+    }
+
+    /// Set the function called when the converter trips one of the fault
+    /// thresholds set with `set_thresholds`.
+    pub fn on_alarm(&mut self, handler: fn(Alarm)) {
+        self.alarm = Some(handler);
+    }
+}
+
+/// Initialize SPI2 for the MAX31865 converters.
+pub fn init_spi<'a>(
+    spi2: &'a stm32::SPI2,
+    pinb12: GpioPinDefault<'a, stm32::GPIOB, Pin12>, 
+    pinb13: GpioPinDefault<'a, stm32::GPIOB, Pin13>,
+    pinb14: GpioPinDefault<'a, stm32::GPIOB, Pin14>, 
+    pinb15: GpioPinDefault<'a, stm32::GPIOB, Pin15>) {
+
+    let pinb12 = pinb12.set_output_10MHz().set_alt_output_push_pull(); // NSS
+    let pinb13 = pinb13.set_output_10MHz().set_alt_output_push_pull(); // SCK
+    let pinb14 = pinb14.set_input().set_floating_input(); // MISO
+    let pinb15 = pinb15.set_output_10MHz().set_alt_output_push_pull(); // MOSI
+
+    let spi2 = Spi(spi2);
+    let r = spi2.start_init();
+
+    let ports = r.set_ports(pinb12, pinb13, pinb14, pinb15);
+
+    spi2.complete_init(ports);
+
+    spi2.listen(false, true);
+}
+
+impl Max31865<Unconfigured> {
+    /// Initialize SPI2 for a single converter selected by the hardware NSS
+    /// output.
+    pub fn new<'a>(
+        spi2: &'a stm32::SPI2,
+        pinb12: GpioPinDefault<'a, stm32::GPIOB, Pin12>, 
+        pinb13: GpioPinDefault<'a, stm32::GPIOB, Pin13>,
+        pinb14: GpioPinDefault<'a, stm32::GPIOB, Pin14>, 
+        pinb15: GpioPinDefault<'a, stm32::GPIOB, Pin15>,
+        probe: ProbeType) -> Max31865<Unconfigured> {
+
+        init_spi(spi2, pinb12, pinb13, pinb14, pinb15);
+
+        Max31865 {
+            conf: Config::new(),
+            probe: probe,
+            cs: None,
+            alarm: None,
+            _state: PhantomData,
+        }
+    }
+
+    /// A converter on the bus initialized by `init_spi` using a software chip
+    /// select, see `use_software_nss`.
+    pub fn with_chip_select(cs: ChipSelect, probe: ProbeType) -> Max31865<Unconfigured> {
+        Max31865 {
+            conf: Config::new(),
+            probe: probe,
+            cs: Some(cs),
+            alarm: None,
+            _state: PhantomData,
+        }
+    }
+
+    /// Write the configuration with the bias voltage switched on and
+    /// conversions off, then read it back to make sure it was applied.
+    pub fn configure<R>(self, t: &mut Threshold, spi: &R, conf: Config) -> Result<Max31865<Configured>, Error>
+    where
+        R : Resource<Data = stm32::SPI2>
+    {
+        let conf = conf
+            .vbias(true)
+            .mode(ConversionMode::NormallyOff)
+            .one_shot(false)
+            .fault_cycle(FaultCycle::None)
+            .fault_clear(false);
+
+        transfer_sync(t, spi, self.cs, |res, spi| res.start_write(REG_CONFIG | REG_WRITE, conf.bits(), spi));
+        let read_back = transfer_sync(t, spi, self.cs, |res, spi| res.start_read(REG_CONFIG, spi));
+
+        if conf.verify(read_back) {
+            Ok(self.into_state(conf))
+        } else {
+            Err(Error::ConfigMismatch(read_back))
+        }
+    }
+}
+
+impl Max31865<Configured> {
+    /// Run the fault detection cycle with automatic delay and return the
+    /// detected faults. The faults are cleared afterwards, also if the cycle
+    /// does not complete in time.
+    pub fn detect_faults<R>(&mut self, t: &mut Threshold, spi: &R) -> Result<FaultStatus, Error>
+    where
+        R : Resource<Data = stm32::SPI2>
+    {
+        let detect = self.conf.automatic_fault_detection();
+        transfer_sync(t, spi, self.cs, |res, spi| res.start_write(REG_CONFIG | REG_WRITE, detect.bits(), spi));
+
+        let mut done = false;
+        for _ in 0..FAULT_CYCLE_POLLS {
+            if fault_cycle_done(transfer_sync(t, spi, self.cs, |res, spi| res.start_read(REG_CONFIG, spi))) {
+                done = true;
+                break;
+            }
+        }
+
+        let status = if done {
+            Ok(FaultStatus(transfer_sync(t, spi, self.cs, |res, spi| res.start_read(REG_FAULT_STATUS, spi))))
+        } else {
+            Err(Error::FaultCycleTimeout)
+        };
+
+        let clear = self.conf.clear_faults();
+        transfer_sync(t, spi, self.cs, |res, spi| res.start_write(REG_CONFIG | REG_WRITE, clear.bits(), spi));
+
+        status
+    }
+
+    /// Program the low and high fault thresholds, given in 1/100 degrees. A
+    /// conversion outside of the thresholds sets the RTD fault bit and raises
+    /// an alarm once the fault status has been read.
+    pub fn set_thresholds<R>(&mut self, t: &mut Threshold, spi: &R, low: i32, high: i32) -> Result<(), Error>
+    where
+        R : Resource<Data = stm32::SPI2>
+    {
+        let low = self.probe.code_for_temperature(low);
+        let high = self.probe.code_for_temperature(high);
+
+        // the codes are left aligned, bit 0 of the LSB is unused
+        let data = [
+            (high >> 7) as u8,
+            (high << 1) as u8,
+            (low >> 7) as u8,
+            (low << 1) as u8,
+        ];
+
+        transfer_sync(t, spi, self.cs, |res, spi| res.start_write_burst(REG_HIGH_FAULT_MSB | REG_WRITE, &data, spi));
+        transfer_sync(t, spi, self.cs, |res, spi| res.start_read_burst(REG_HIGH_FAULT_MSB, data.len(), spi));
+
+        let mut read_back = [0u8; 4];
+        unsafe { SPI_RES.result_into(&mut read_back); }
+
+        if read_back == data {
+            Ok(())
+        } else {
+            Err(Error::ThresholdMismatch)
+        }
+    }
+
+    /// Start continuous conversions, a new result is signalled on DRDY at
+    /// the filter rate.
+    pub fn start_auto<R>(self, t: &mut Threshold, spi: &R) -> Max31865<Converting>
+    where
+        R : Resource<Data = stm32::SPI2>
+    {
+        let conf = self.conf.mode(ConversionMode::Auto);
+        transfer_sync(t, spi, self.cs, |res, spi| res.start_write(REG_CONFIG | REG_WRITE, conf.bits(), spi));
+        self.into_state(conf)
+    }
+
+    /// Start a single conversion, signalled on DRDY after 52 ms (60 Hz) or
+    /// 62.5 ms (50 Hz). The bus has to be idle.
+    pub fn start_one_shot<'a>(self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) -> Max31865<Converting> {
+        let conf = self.conf;
+        spi_res.select(self.cs);
+        spi_res.start_write(REG_CONFIG | REG_WRITE, conf.one_shot(true).bits(), spi);
+        self.into_state(conf)
+    }
+
+    /// Switch the bias voltage off to reduce self heating of the RTD. The bus
+    /// has to be idle.
+    pub fn bias_off<'a>(self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) -> Max31865<Standby> {
+        let conf = self.conf.vbias(false);
+        spi_res.select(self.cs);
+        spi_res.start_write(REG_CONFIG | REG_WRITE, conf.bits(), spi);
+        self.into_state(conf)
+    }
+}
+
+impl Max31865<Standby> {
+    /// Switch the bias voltage back on. The input filter has to settle for
+    /// `BIAS_SETTLE_TICKS` before a conversion is started. The bus has to be
+    /// idle.
+    pub fn bias_on<'a>(self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) -> Max31865<Configured> {
+        let conf = self.conf.vbias(true);
+        spi_res.select(self.cs);
+        spi_res.start_write(REG_CONFIG | REG_WRITE, conf.bits(), spi);
+        self.into_state(conf)
+    }
+}
+
+impl Max31865<Converting> {
+    /// Stop automatic conversions, leaving the bias voltage on.
+    pub fn stop<R>(self, t: &mut Threshold, spi: &R) -> Max31865<Configured>
+    where
+        R : Resource<Data = stm32::SPI2>
+    {
+        let conf = self.conf.mode(ConversionMode::NormallyOff);
+        transfer_sync(t, spi, self.cs, |res, spi| res.start_write(REG_CONFIG | REG_WRITE, conf.bits(), spi));
+        self.into_state(conf)
+    }
+
+    /// Stop converting and switch the bias voltage off. The bus has to be
+    /// idle.
+    pub fn finish<'a>(self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) -> Max31865<Standby> {
+        let conf = self.conf.mode(ConversionMode::NormallyOff).vbias(false);
+        spi_res.select(self.cs);
+        spi_res.start_write(REG_CONFIG | REG_WRITE, conf.bits(), spi);
+        self.into_state(conf)
+    }
+
+    /// Start reading the RTD registers, once `SPI_RES` has finished the
+    /// result is available from `rtd`.
+    pub fn start_read<'a>(&self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) {
+        spi_res.select(self.cs);
+        spi_res.start_read_burst(REG_RTD_MSB, 2, spi);
+    }
+
+    /// The 15 bit RTD code from a finished `start_read`.
+    pub fn rtd(&self, spi_res: &SpiResource) -> Result<u16, Error> {
+        let mut buf = [0u8; 2];
+        spi_res.result_into(&mut buf);
+
+        if rtd_fault(buf[1]) {
+            Err(Error::RtdFault)
+        } else {
+            Ok((((buf[0] as u16) << 8) | (buf[1] as u16)) >> 1)
+        }
+    }
+
+    /// Start reading the fault status register, once finished the result is
+    /// available from `fault_status`.
+    pub fn start_read_faults<'a>(&self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) {
+        spi_res.select(self.cs);
+        spi_res.start_read(REG_FAULT_STATUS, spi);
+    }
+
+    /// The fault status from a finished `start_read_faults`. Calls the alarm
+    /// handler if a fault threshold has been tripped.
+    pub fn fault_status(&self, spi_res: &SpiResource) -> FaultStatus {
+        let status = FaultStatus(spi_res.result());
+
+        if let Some(handler) = self.alarm {
+            if status.is_set(Fault::RtdHighThreshold) {
+                handler(Alarm::High);
+            }
+            if status.is_set(Fault::RtdLowThreshold) {
+                handler(Alarm::Low);
+            }
+        }
+
+        status
+    }
+
+    /// Start clearing the fault status.
+    pub fn start_clear_faults<'a>(&self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) {
+        spi_res.select(self.cs);
+        spi_res.start_write(REG_CONFIG | REG_WRITE, self.conf.clear_faults().bits(), spi);
+    }
+}
+
+/// Number of timer ticks to wait after switching on the bias voltage before
+/// starting a 1-shot conversion. The datasheet requires 10.5 time constants of
+/// the input filter plus 1 ms.
+pub const BIAS_SETTLE_TICKS : u64 = 10;
+
+/// External interrupt line connected to the DRDY output of a converter.
+#[derive(Clone, Copy, PartialEq)]
+pub struct DrdyLine {
+    /// GPIO port, 0 for port A, 1 for port B, ...
+    pub port : u8,
+    /// Pin number of the port, which is also the EXTI line
+    pub line : u8,
+}
+
+impl DrdyLine {
+    pub fn new(port: u8, line: u8) -> DrdyLine {
+        DrdyLine {
+            port: port,
+            line: line,
+        }
+    }
+
+    /// Route the pin to its EXTI line and enable the interrupt on the falling
+    /// edge. The pin has to be an input, which it is after reset. Note that the
+    /// application only handles the interrupt of lines 5 to 9.
+    pub fn enable(&self, afio: &stm32::AFIO, exti: &stm32::EXTI) {
+        let shift = (self.line as u32 % 4) * 4;
+        let mask = !(0xF << shift);
+        let port = (self.port as u32) << shift;
+
+        unsafe {
+            match self.line / 4 {
+                0 => afio.exticr1.modify(|r, w| w.bits((r.bits() & mask) | port)),
+                1 => afio.exticr2.modify(|r, w| w.bits((r.bits() & mask) | port)),
+                2 => afio.exticr3.modify(|r, w| w.bits((r.bits() & mask) | port)),
+                _ => afio.exticr4.modify(|r, w| w.bits((r.bits() & mask) | port)),
+            }
+
+            exti.imr.modify(|r, w| w.bits(r.bits() | self.mask()));
+            exti.ftsr.modify(|r, w| w.bits(r.bits() | self.mask()));
+        }
+    }
+
+    /// Mask the interrupt of a line whose converter is not used and drop an
+    /// edge which is already pending.
+    pub fn disable(&self, exti: &stm32::EXTI) {
+        unsafe {
+            exti.imr.modify(|r, w| w.bits(r.bits() & !self.mask()));
+            exti.ftsr.modify(|r, w| w.bits(r.bits() & !self.mask()));
+        }
+        self.clear(exti);
+    }
+
+    fn mask(&self) -> u32 {
+        1 << self.line
+    }
+
+    pub fn pending(&self, exti: &stm32::EXTI) -> bool {
+        exti.pr.read().bits() & self.mask() != 0
+    }
+
+    pub fn clear(&self, exti: &stm32::EXTI) {
+        // pending bits are cleared by writing a one
+        exti.pr.write(|w| unsafe { w.bits(self.mask()) });
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sampling {
+    /// Automatic conversions, each result is read on the DRDY falling edge
+    Continuous,
+    /// 1-shot conversions started every given number of timer ticks, with the
+    /// bias voltage switched off in between
+    OneShot(u64),
+}
+
+/// Results produced by the scheduler.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Event {
+    /// A new 15 bit RTD code
+    Rtd(u16),
+    /// The converter reported a fault, it has been cleared again
+    Fault(FaultStatus),
+}
+
+enum Stage {
+    /// Placeholder while the driver is moved between stages
+    Moving,
+    /// Bias off, the next 1-shot starts at the given tick
+    Standby(Max31865<Standby>, u64),
+    /// Bias on, the 1-shot starts once the filter has settled at the given tick
+    Settling(Max31865<Configured>, u64),
+    /// Waiting for DRDY
+    Converting(Max31865<Converting>),
+}
+
+/// The transaction on `SPI_RES` issued by the scheduler.
+#[derive(Clone, Copy, PartialEq)]
+enum Pending {
+    Nothing,
+    Config,
+    Rtd,
+    Fault,
+    ClearFault,
+}
+
+/// Schedules the conversions of a MAX31865 and reads the results.
+///
+/// The scheduler only uses the bus when `poll` is called, so several
+/// schedulers can share it, see `Probes`. Once the transaction started by
+/// `poll` has finished, `on_spi` has to be called, which may continue with
+/// further transactions.
+pub struct Scheduler {
+    sampling : Sampling,
+    drdy : DrdyLine,
+    stage : Stage,
+    pending : Pending,
+    ready : bool,
+    last_start : u64,
+}
+
+impl Scheduler {
+    /// Start sampling on a configured converter. For continuous sampling this
+    /// starts automatic conversions and blocks until they are.
+    pub fn start<R>(
+        max: Max31865<Configured>,
+        sampling: Sampling,
+        drdy: DrdyLine,
+        t: &mut Threshold,
+        spi: &R) -> Scheduler
+    where
+        R : Resource<Data = stm32::SPI2>
+    {
+        let stage = match sampling {
+            Sampling::Continuous => Stage::Converting(max.start_auto(t, spi)),
+            Sampling::OneShot(_) => Stage::Settling(max, 0),
+        };
+
+        Scheduler {
+            sampling: sampling,
+            drdy: drdy,
+            stage: stage,
+            pending: Pending::Nothing,
+            // read the first conversion in case DRDY is already low
+            ready: sampling == Sampling::Continuous,
+            last_start: 0,
+        }
+    }
+
+    pub fn drdy(&self) -> DrdyLine {
+        self.drdy
+    }
+
+    pub fn probe_type(&self) -> Option<ProbeType> {
+        match self.stage {
+            Stage::Standby(ref max, _) => Some(max.probe_type()),
+            Stage::Settling(ref max, _) => Some(max.probe_type()),
+            Stage::Converting(ref max) => Some(max.probe_type()),
+            Stage::Moving => None,
+        }
+    }
+
+    /// Note that a conversion has finished, the result is read on the next
+    /// `poll`.
+    pub fn on_drdy(&mut self) {
+        self.ready = true;
+    }
+
+    /// Start the next transaction if there is one, `now` is the current timer
+    /// tick. Returns true if a transaction has been started.
+    pub fn poll<'a>(&mut self, now: u64, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) -> bool {
+        if spi_res.busy() || self.pending != Pending::Nothing {
+            return false;
+        }
+
+        if self.ready {
+            if let Stage::Converting(ref max) = self.stage {
+                self.ready = false;
+                max.start_read(spi_res, spi);
+                self.pending = Pending::Rtd;
+                return true;
+            }
+        }
+
+        self.stage = match mem::replace(&mut self.stage, Stage::Moving) {
+            Stage::Standby(max, start) => {
+                if now >= start {
+                    self.pending = Pending::Config;
+                    Stage::Settling(max.bias_on(spi_res, spi), now + BIAS_SETTLE_TICKS)
+                } else {
+                    Stage::Standby(max, start)
+                }
+            }
+            Stage::Settling(max, settled) => {
+                if now >= settled {
+                    self.pending = Pending::Config;
+                    self.last_start = now;
+                    Stage::Converting(max.start_one_shot(spi_res, spi))
+                } else {
+                    Stage::Settling(max, settled)
+                }
+            }
+            stage => stage
+        };
+
+        self.pending != Pending::Nothing
+    }
+
+    /// Handle a finished transaction of `SPI_RES`.
+    pub fn on_spi<'a>(&mut self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) -> Option<Event> {
+        let pending = self.pending;
+        self.pending = Pending::Nothing;
+
+        let mut event = None;
+        let mut done = false;
+
+        if let Stage::Converting(ref max) = self.stage {
+            match pending {
+                Pending::Rtd => {
+                    match max.rtd(spi_res) {
+                        Ok(val) => {
+                            event = Some(Event::Rtd(val));
+                            done = true;
+                        }
+                        Err(_) => {
+                            max.start_read_faults(spi_res, spi);
+                            self.pending = Pending::Fault;
+                        }
+                    }
+                }
+                Pending::Fault => {
+                    event = Some(Event::Fault(max.fault_status(spi_res)));
+                    max.start_clear_faults(spi_res, spi);
+                    self.pending = Pending::ClearFault;
+                }
+                Pending::ClearFault => {
+                    done = true;
+                }
+                _ => {}
+            }
+        }
+
+        // a 1-shot conversion has been read, switch the bias off until the next
+        if let (true, Sampling::OneShot(period)) = (done, self.sampling) {
+            self.stage = match mem::replace(&mut self.stage, Stage::Moving) {
+                Stage::Converting(max) => {
+                    self.pending = Pending::Config;
+                    Stage::Standby(max.finish(spi_res, spi), self.last_start + period)
+                }
+                stage => stage
+            };
+        }
+
+        event
+    }
+}
+
+/// Maximum number of converters sharing the bus.
+pub const MAX_PROBES : usize = 3;
+
+/// Identifies a converter, the index in which it was added to `Probes`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ProbeId(pub u8);
+
+fn sensor_error(e: ConversionError) -> SensorError {
+    match e {
+        ConversionError::Implausible => SensorError::Implausible,
+        _ => SensorError::OutOfRange,
+    }
+}
+
+enum Converter {
+    Rtd(Scheduler),
+    Thermocouple(thermocouple::Scheduler),
+}
+
+impl Converter {
+    fn poll<'a>(&mut self, now: u64, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) -> bool {
+        match *self {
+            Converter::Rtd(ref mut scheduler) => scheduler.poll(now, spi_res, spi),
+            Converter::Thermocouple(ref mut scheduler) => scheduler.poll(now, spi_res, spi),
+        }
+    }
+
+    /// Handle a finished transaction, returns the uncalibrated temperature
+    /// if one was read.
+    fn on_spi<'a>(&mut self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) -> Option<Result<i32, SensorError>> {
+        match *self {
+            Converter::Rtd(ref mut scheduler) => match scheduler.on_spi(spi_res, spi) {
+                Some(Event::Rtd(code)) => scheduler.probe_type()
+                    .map(|probe_type| probe_type.temperature(code).map_err(sensor_error)),
+                Some(Event::Fault(status)) => rtd_fault_error(status),
+                None => None
+            },
+            Converter::Thermocouple(ref mut scheduler) =>
+                Some(scheduler.on_spi(spi_res).map_err(|fault| SensorError::Fault(fault.code()))),
+        }
+    }
+}
+
+/// Faults reported by the converter, `None` if they have been cleared.
+fn rtd_fault_error(status: FaultStatus) -> Option<Result<i32, SensorError>> {
+    status.first().map(|fault| Err(SensorError::Fault(fault.code())))
+}
+
+/// Several converters sharing SPI2, each with its own chip select. The RTD
+/// converters signal DRDY, the thermocouple converters are read at a fixed
+/// interval. The bus is given to the converters in round robin order.
+pub struct Probes {
+    probes : [Option<Converter>; MAX_PROBES],
+    calibration : [Calibration; MAX_PROBES],
+    status : [Status; MAX_PROBES],
+    /// Readings not taken yet, the newest of each probe
+    readings : [Option<Reading>; MAX_PROBES],
+    /// The probe which started the current transaction
+    owner : Option<usize>,
+    /// The probe polled first for the next transaction
+    next : usize,
+}
+
+impl Probes {
+    pub fn new() -> Probes {
+        Probes {
+            probes: [None, None, None],
+            calibration: [Calibration::None; MAX_PROBES],
+            status: [Status::Stopped; MAX_PROBES],
+            readings: [None; MAX_PROBES],
+            owner: None,
+            next: 0,
+        }
+    }
+
+    /// Add an RTD converter, returns `None` if there are already `MAX_PROBES`.
+    pub fn add(&mut self, scheduler: Scheduler) -> Option<ProbeId> {
+        self.insert(Converter::Rtd(scheduler))
+    }
+
+    /// Add a thermocouple converter, returns `None` if there are already
+    /// `MAX_PROBES`.
+    pub fn add_thermocouple(&mut self, scheduler: thermocouple::Scheduler) -> Option<ProbeId> {
+        self.insert(Converter::Thermocouple(scheduler))
+    }
+
+    fn insert(&mut self, converter: Converter) -> Option<ProbeId> {
+        for (i, probe) in self.probes.iter_mut().enumerate() {
+            if probe.is_none() {
+                *probe = Some(converter);
+                self.status[i] = Status::Running;
+                return Some(ProbeId(i as u8));
+            }
+        }
+        None
+    }
+
+    /// The RTD of `probe`, `None` for thermocouples.
+    pub fn probe_type(&self, probe: ProbeId) -> Option<ProbeType> {
+        match self.probes[probe.0 as usize] {
+            Some(Converter::Rtd(ref scheduler)) => scheduler.probe_type(),
+            _ => None
+        }
+    }
+
+    pub fn calibration(&self, probe: ProbeId) -> Calibration {
+        self.calibration[probe.0 as usize]
+    }
+
+    /// Handle the external interrupt, clearing the pending DRDY lines.
+    pub fn on_drdy(&mut self, exti: &stm32::EXTI) {
+        for probe in self.probes.iter_mut() {
+            if let Some(Converter::Rtd(ref mut scheduler)) = *probe {
+                let drdy = scheduler.drdy();
+                if drdy.pending(exti) {
+                    scheduler.on_drdy();
+                    drdy.clear(exti);
+                }
+            }
+        }
+    }
+
+    /// Give the bus to the next probe which has something to do.
+    pub fn poll<'a>(&mut self, now: u64, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) {
+        if spi_res.busy() || self.owner.is_some() {
+            return;
+        }
+
+        for i in 0..MAX_PROBES {
+            let idx = (self.next + i) % MAX_PROBES;
+            if let Some(ref mut converter) = self.probes[idx] {
+                if converter.poll(now, spi_res, spi) {
+                    self.owner = Some(idx);
+                    self.next = (idx + 1) % MAX_PROBES;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// True if `channel` is one of these probes rather than of another sensor.
+    fn owns(&self, channel: u8) -> bool {
+        match self.probes.get(channel as usize) {
+            Some(&Some(_)) => true,
+            _ => false
+        }
+    }
+
+    /// Report faults found by `Max31865::detect_faults` before `probe` was
+    /// added.
+    pub fn report_faults(&mut self, now: u64, probe: ProbeId, status: FaultStatus) {
+        if let Some(result) = rtd_fault_error(status) {
+            self.on_result(now, probe.0 as usize, result);
+        }
+    }
+
+    /// Handle a finished transaction of `SPI_RES`, a reading can then be
+    /// taken if the sequence of the probe produced one.
+    pub fn on_spi<'a>(&mut self, now: u64, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) {
+        spi_res.restore_mode(spi);
+
+        let owner = match self.owner {
+            Some(owner) => owner,
+            None => return
+        };
+
+        let result = match self.probes[owner] {
+            Some(ref mut converter) => converter.on_spi(spi_res, spi),
+            None => None
+        };
+
+        // the probe keeps the bus while it continues with its sequence
+        if !spi_res.busy() {
+            self.owner = None;
+            self.poll(now, spi_res, spi);
+        }
+
+        if let Some(result) = result {
+            self.on_result(now, owner, result);
+        }
+    }
+
+    fn on_result(&mut self, now: u64, probe: usize, result: Result<i32, SensorError>) {
+        let calibration = self.calibration[probe];
+        let value = result.map(|temp| Measurement {
+            temperature: Temperature(calibration.apply(temp)),
+            uncalibrated: Temperature(temp),
+        });
+
+        self.status[probe] = match value {
+            Ok(_) => Status::Running,
+            Err(e) => Status::Failed(e),
+        };
+        self.readings[probe] = Some(Reading {
+            channel: probe as u8,
+            time: now,
+            value: value,
+        });
+    }
+}
+
+impl TemperatureSensor for Probes {
+    fn channels(&self) -> usize {
+        self.probes.iter().filter(|p| p.is_some()).count()
+    }
+
+    fn status(&self, channel: u8) -> Status {
+        if self.owns(channel) {
+            self.status[channel as usize]
+        } else {
+            Status::Stopped
+        }
+    }
+
+    fn take(&mut self) -> Option<Reading> {
+        for reading in self.readings.iter_mut() {
+            if reading.is_some() {
+                return reading.take();
+            }
+        }
+        None
+    }
+
+    fn set_calibration(&mut self, channel: u8, calibration: Calibration) {
+        if self.owns(channel) {
+            self.calibration[channel as usize] = calibration;
+        }
+    }
 }
\ No newline at end of file
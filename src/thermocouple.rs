@@ -0,0 +1,294 @@
+#[macro_use]
+#[allow(unused_imports)]
+use debug;
+use stm32;
+
+use spi::Spi;
+use rtfm::{Resource, Threshold};
+use tempsensor::{self, ChipSelect, SpiResource, Error};
+
+/// Fault reported by a thermocouple converter.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The thermocouple is not connected
+    Open,
+    /// The thermocouple is shorted to GND
+    ShortToGnd,
+    /// The thermocouple is shorted to VCC
+    ShortToVcc,
+    /// The thermocouple inputs are shorted to GND or VCC (MAX31856)
+    OverUnderVoltage,
+    /// The thermocouple temperature is out of range of the type (MAX31856)
+    ThermocoupleRange,
+    /// The cold junction temperature is out of range (MAX31856)
+    ColdJunctionRange,
+}
+
+impl Fault {
+    /// Code shown on the display.
+    pub fn code(&self) -> u8 {
+        match *self {
+            Fault::Open => 1,
+            Fault::ShortToGnd => 2,
+            Fault::ShortToVcc => 3,
+            Fault::OverUnderVoltage => 4,
+            Fault::ThermocoupleRange => 5,
+            Fault::ColdJunctionRange => 6,
+        }
+    }
+}
+
+/// The 32 bit frame read from a MAX31855.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Frame(pub u32);
+
+const FRAME_FAULT : u32 = 1 << 16;
+const FRAME_SCV : u32 = 1 << 2;
+const FRAME_SCG : u32 = 1 << 1;
+const FRAME_OC : u32 = 1 << 0;
+
+impl Frame {
+    pub fn from_bytes(b: &[u8; 4]) -> Frame {
+        Frame((b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32)
+    }
+
+    pub fn fault(&self) -> Option<Fault> {
+        if self.0 & FRAME_FAULT == 0 {
+            None
+        } else if self.0 & FRAME_OC != 0 {
+            Some(Fault::Open)
+        } else if self.0 & FRAME_SCG != 0 {
+            Some(Fault::ShortToGnd)
+        } else if self.0 & FRAME_SCV != 0 {
+            Some(Fault::ShortToVcc)
+        } else {
+            Some(Fault::Open)
+        }
+    }
+
+    /// Thermocouple temperature in 1/100 degrees, 14 bits in 1/4 degrees.
+    pub fn thermocouple(&self) -> i32 {
+        ((self.0 as i32) >> 18) * 25
+    }
+
+    /// Cold junction temperature in 1/100 degrees, 12 bits in 1/16 degrees.
+    pub fn cold_junction(&self) -> i32 {
+        ((self.0 as i32) << 16 >> 20) * 625 / 100
+    }
+
+    pub fn temperature(&self) -> Result<i32, Fault> {
+        match self.fault() {
+            Some(fault) => Err(fault),
+            None => Ok(self.thermocouple())
+        }
+    }
+}
+
+/// MAX31855, which continuously converts and only has to be read.
+///
+/// The MAX31855 changes its output on the falling edge of SCK, so its frame
+/// is read in SPI mode 0, see `SpiResource::start_read_frame`.
+#[derive(Clone, Copy)]
+pub struct Max31855 {
+    cs : ChipSelect,
+}
+
+impl Max31855 {
+    pub fn new(cs: ChipSelect) -> Max31855 {
+        Max31855 { cs: cs }
+    }
+
+    pub fn start_read<'a>(&self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) {
+        spi_res.select(Some(self.cs));
+        spi_res.start_read_frame(4, spi);
+    }
+
+    /// The frame read by `start_read`.
+    pub fn frame(&self, spi_res: &SpiResource) -> Frame {
+        let mut buf = [0u8; 4];
+        spi_res.result_into(&mut buf);
+        Frame::from_bytes(&buf)
+    }
+}
+
+pub const REG_CR0 : u8 = 0x00;
+pub const REG_CR1 : u8 = 0x01;
+pub const REG_MASK : u8 = 0x02;
+pub const REG_CJTH : u8 = 0x0A;
+pub const REG_LTCBH : u8 = 0x0C;
+pub const REG_SR : u8 = 0x0F;
+pub const REG_WRITE : u8 = 0x80;
+
+const CR0_CMODE : u8 = 1 << 7;
+const CR0_OCFAULT_1 : u8 = 1 << 4;
+const CR0_50HZ : u8 = 1 << 0;
+
+const SR_CJRANGE : u8 = 1 << 7;
+const SR_TCRANGE : u8 = 1 << 6;
+const SR_OVUV : u8 = 1 << 1;
+const SR_OPEN : u8 = 1 << 0;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ThermocoupleType {
+    B = 0,
+    E = 1,
+    J = 2,
+    K = 3,
+    N = 4,
+    R = 5,
+    S = 6,
+    T = 7,
+}
+
+/// Configuration of a MAX31856, which converts automatically with open
+/// circuit detection and faults in comparator mode, so they clear themselves.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Config {
+    pub tc_type : ThermocoupleType,
+    /// Number of samples averaged as a power of two, at most 4
+    pub averaging : u8,
+    pub filter : tempsensor::Filter,
+}
+
+impl Config {
+    pub fn new(tc_type: ThermocoupleType) -> Config {
+        Config {
+            tc_type: tc_type,
+            averaging: 0,
+            filter: tempsensor::Filter::Hz50,
+        }
+    }
+
+    pub fn averaging(self, averaging: u8) -> Config {
+        Config { averaging: if averaging > 4 { 4 } else { averaging }, ..self }
+    }
+
+    pub fn filter(self, filter: tempsensor::Filter) -> Config {
+        Config { filter: filter, ..self }
+    }
+
+    pub fn cr0(&self) -> u8 {
+        let filter = match self.filter {
+            tempsensor::Filter::Hz50 => CR0_50HZ,
+            tempsensor::Filter::Hz60 => 0,
+        };
+        CR0_CMODE | CR0_OCFAULT_1 | filter
+    }
+
+    pub fn cr1(&self) -> u8 {
+        self.averaging << 4 | self.tc_type as u8
+    }
+}
+
+/// MAX31856, read with a burst of the temperature and status registers.
+#[derive(Clone, Copy)]
+pub struct Max31856 {
+    cs : ChipSelect,
+}
+
+impl Max31856 {
+    pub fn new(cs: ChipSelect) -> Max31856 {
+        Max31856 { cs: cs }
+    }
+
+    /// Write the configuration and start the automatic conversions.
+    pub fn configure<R>(&self, t: &mut Threshold, spi: &R, conf: Config) -> Result<(), Error>
+    where
+        R : Resource<Data = stm32::SPI2>
+    {
+        let cs = Some(self.cs);
+        tempsensor::transfer_sync(t, spi, cs, |res, spi| res.start_write_burst(REG_CR0 | REG_WRITE, &[conf.cr0(), conf.cr1()], spi));
+        // report all faults in the status register but not on the FAULT pin
+        tempsensor::transfer_sync(t, spi, cs, |res, spi| res.start_write(REG_MASK | REG_WRITE, 0xFF, spi));
+
+        let read_back = tempsensor::transfer_sync(t, spi, cs, |res, spi| res.start_read(REG_CR0, spi));
+        if read_back == conf.cr0() {
+            Ok(())
+        } else {
+            Err(Error::ConfigMismatch(read_back))
+        }
+    }
+
+    /// Start reading the linearized temperature and the fault status.
+    pub fn start_read<'a>(&self, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) {
+        spi_res.select(Some(self.cs));
+        spi_res.start_read_burst(REG_LTCBH, 4, spi);
+    }
+
+    /// The temperature in 1/100 degrees read by `start_read`.
+    pub fn temperature(&self, spi_res: &SpiResource) -> Result<i32, Fault> {
+        let mut buf = [0u8; 4];
+        spi_res.result_into(&mut buf);
+
+        let status = buf[3];
+        if status & SR_OPEN != 0 {
+            return Err(Fault::Open);
+        } else if status & SR_OVUV != 0 {
+            return Err(Fault::OverUnderVoltage);
+        } else if status & SR_TCRANGE != 0 {
+            return Err(Fault::ThermocoupleRange);
+        } else if status & SR_CJRANGE != 0 {
+            return Err(Fault::ColdJunctionRange);
+        }
+
+        // 19 bits in 1/128 degrees
+        let raw = ((buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8) as i32 >> 13;
+        Ok(raw * 100 / 128)
+    }
+}
+
+/// Converter type of a thermocouple, with the configuration of a MAX31856.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Chip {
+    Max31855,
+    Max31856(Config),
+}
+
+#[derive(Clone, Copy)]
+pub enum Device {
+    Max31855(Max31855),
+    Max31856(Max31856),
+}
+
+/// Reads a thermocouple converter every `interval` ticks, see
+/// `tempsensor::Probes::add_thermocouple`.
+pub struct Scheduler {
+    device : Device,
+    interval : u64,
+    last_start : Option<u64>,
+}
+
+impl Scheduler {
+    pub fn new(device: Device, interval: u64) -> Scheduler {
+        Scheduler {
+            device: device,
+            interval: interval,
+            last_start: None,
+        }
+    }
+
+    /// Start a read if it is due, returns whether a transaction was started.
+    pub fn poll<'a>(&mut self, now: u64, spi_res: &mut SpiResource, spi: &Spi<'a, stm32::SPI2>) -> bool {
+        if let Some(last) = self.last_start {
+            if now < last + self.interval {
+                return false;
+            }
+        }
+
+        self.last_start = Some(now);
+        match self.device {
+            Device::Max31855(ref max) => max.start_read(spi_res, spi),
+            Device::Max31856(ref max) => max.start_read(spi_res, spi),
+        }
+        true
+    }
+
+    /// The temperature in 1/100 degrees read by the transaction started by
+    /// `poll`.
+    pub fn on_spi(&mut self, spi_res: &SpiResource) -> Result<i32, Fault> {
+        match self.device {
+            Device::Max31855(ref max) => max.frame(spi_res).temperature(),
+            Device::Max31856(ref max) => max.temperature(spi_res),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,70 @@
+use tempsensor::MAX_PROBES;
+
+/// Freshness deadlines of the temperature channels. A channel becomes stale
+/// if it did not produce any reading within its deadline.
+pub struct Watchdog {
+    /// Maximum ticks between readings of each channel
+    deadlines : [Option<u64>; MAX_PROBES],
+    last : [u64; MAX_PROBES],
+    stale : [bool; MAX_PROBES],
+    on_stale : Option<fn(u8)>,
+}
+
+pub static mut WATCHDOG : Watchdog = Watchdog {
+    deadlines: [None; MAX_PROBES],
+    last: [0; MAX_PROBES],
+    stale: [false; MAX_PROBES],
+    on_stale: None,
+};
+
+impl Watchdog {
+    /// Start watching `channel`, which must produce a reading every
+    /// `deadline` ticks from `now` on.
+    pub fn watch(&mut self, channel: u8, deadline: u64, now: u64) {
+        let idx = channel as usize;
+        self.deadlines[idx] = Some(deadline);
+        self.last[idx] = now;
+        self.stale[idx] = false;
+    }
+
+    /// Register a handler called when a channel becomes stale, which should
+    /// put anything depending on the temperature into a safe state.
+    pub fn on_stale(&mut self, handler: fn(u8)) {
+        self.on_stale = Some(handler);
+    }
+
+    /// Record a reading of `channel` taken at `time`, returns whether the
+    /// channel was stale before.
+    pub fn feed(&mut self, channel: u8, time: u64) -> bool {
+        let idx = channel as usize;
+        self.last[idx] = time;
+        let was_stale = self.stale[idx];
+        self.stale[idx] = false;
+        was_stale
+    }
+
+    pub fn is_stale(&self, channel: u8) -> bool {
+        self.stale[channel as usize]
+    }
+
+    pub fn any_stale(&self) -> bool {
+        self.stale.iter().any(|s| *s)
+    }
+
+    /// Check the deadlines, returns the next channel which became stale.
+    /// Call repeatedly until it returns `None`.
+    pub fn check(&mut self, now: u64) -> Option<u8> {
+        for idx in 0..MAX_PROBES {
+            if let Some(deadline) = self.deadlines[idx] {
+                if !self.stale[idx] && now > self.last[idx] + deadline {
+                    self.stale[idx] = true;
+                    if let Some(handler) = self.on_stale {
+                        handler(idx as u8);
+                    }
+                    return Some(idx as u8);
+                }
+            }
+        }
+        None
+    }
+}
\ No newline at end of file